@@ -19,7 +19,8 @@ class MqttServer
 end
 
 class MqttClient
-  def initialize(port = 1883)
+  def initialize(port = 1883, client_id = 'cid')
+    @client_id = client_id
     Timeout.timeout(1) do
       while @socket.nil?
         begin
@@ -53,13 +54,13 @@ class MqttClient
   end
 
   def send_mqtt_connect
-    send_bytes [0x10, 0x2a, # fixed header
+    send_bytes [0x10, 0x27 + @client_id.length, # fixed header
                 0x00, 0x06] + 'MQIsdp'.unpack('C*') + \
     [0x03, # protocol version
      0xcc, # connection flags 1100111x user, pw, !wr, w(01), w, !c, x
      0x00, 0x0a, # keepalive of 10
-     0x00, 0x03, 'c'.ord, 'i'.ord, 'd'.ord, # client ID
-     0x00, 0x04, 'w'.ord, 'i'.ord, 'l'.ord, 'l'.ord, # will topic
+     0x00, @client_id.length] + string_to_ints(@client_id) + # client ID
+    [0x00, 0x04, 'w'.ord, 'i'.ord, 'l'.ord, 'l'.ord, # will topic
      0x00, 0x04, 'w'.ord, 'm'.ord, 's'.ord, 'g'.ord, # will msg
      0x00, 0x07, 'g'.ord, 'l'.ord, 'i'.ord, 'f'.ord, 't'.ord, 'e'.ord, 'l'.ord, # username
      0x00, 0x02, 'p'.ord, 'w'.ord] # password
@@ -110,9 +111,10 @@ After do
   @mqtt.finalize
 end
 
+# each client gets its own id, otherwise they'd all be sharing one session
 def connect_to_broker_tcp(port = 1883)
   @clients ||= []
-  @clients << MqttClient.new(port)
+  @clients << MqttClient.new(port, "cid#{@clients.length}")
 end
 
 Given(/^I have established a TCP connection to the broker on port (\d+)$/) do |port|
@@ -144,6 +146,16 @@ Then(/^I should receive a SUBACK message with qos (\d+) and msgId (\d+)$/) do |q
   @clients[0].recv_suback(msg_id.to_i, qos.to_i)
 end
 
+When(/^I subscribe to "(.*?)" with qos (\d+) and "(.*?)" with qos (\d+) with msgId (\d+)$/) do |topic1, qos1, topic2, qos2, msg_id|
+  payload = [0, topic1.length] + string_to_ints(topic1) + [qos1.to_i] + \
+            [0, topic2.length] + string_to_ints(topic2) + [qos2.to_i]
+  @clients[0].send_bytes [0x82, 2 + payload.length, 0, msg_id.to_i] + payload
+end
+
+Then(/^I should receive a SUBACK message with return codes (\d+) and (\d+) and msgId (\d+)$/) do |code1, code2, msg_id|
+  @clients[0].assert_recv [0x90, 4, 0, msg_id.to_i, code1.to_i, code2.to_i]
+end
+
 def publish(client, topic, payload)
   remaining_length = topic.length + 2 + payload.length
   client.send_bytes [0x30, remaining_length, 0, topic.length] + \
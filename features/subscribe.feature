@@ -7,3 +7,8 @@ Feature: Subscribe
     Given I have connected to the broker on port 1883
     When I subscribe to one topic with msgId 42
     Then I should receive a SUBACK message with qos 0 and msgId 42
+
+  Scenario: Subscribe to two topics
+    Given I have connected to the broker on port 1883
+    When I subscribe to "first" with qos 1 and "second" with qos 2 with msgId 7
+    Then I should receive a SUBACK message with return codes 1 and 2 and msgId 7
@@ -1,3 +1,5 @@
+use message;
+
 use std::rc::{Rc};
 use std::cell::{RefCell};
 use std::collections::HashMap;
@@ -19,13 +21,14 @@ fn is_same_subscriber<T: Subscriber>(lhs: Rc<RefCell<T>>, rhs: Rc<RefCell<T>>) -
 }
 
 pub trait Subscriber {
-    fn new_message(&mut self, bytes: &[u8]);
+    fn new_message(&mut self, publish: &message::Publish);
 }
 
 pub struct Broker<T: Subscriber> {
     tree: Node<T>,
     use_cache: bool,
-    cache: HashMap<String, Vec<Rc<RefCell<T>>>>,
+    cache: HashMap<String, Vec<(Rc<RefCell<T>>, u8)>>, //subscribers and their QoS
+    retained: HashMap<String, message::Publish>, //the last retained message for each topic
 }
 
 struct Node<T: Subscriber> {
@@ -36,11 +39,37 @@ struct Node<T: Subscriber> {
 struct Subscription<T: Subscriber> {
     subscriber: Rc<RefCell<T>>,
     topic: String,
+    qos: u8, //the maximum QoS it gets messages at
 }
 
 impl<T: Subscriber> Subscription<T> {
-    fn new(subscriber: Rc<RefCell<T>>, topic: &str) -> Self {
-        Subscription { subscriber: subscriber.clone(), topic: topic.to_string() }
+    fn new(subscriber: Rc<RefCell<T>>, topic: &str, qos: u8) -> Self {
+        Subscription { subscriber: subscriber.clone(), topic: topic.to_string(), qos: qos }
+    }
+}
+
+//messages are delivered at whichever is lower, the QoS they were published with
+//or the one the subscriber asked for
+fn deliver<T: Subscriber>(subscriber: &Rc<RefCell<T>>, publish: &message::Publish, qos: u8) {
+    if publish.qos <= qos {
+        subscriber.borrow_mut().new_message(publish);
+    } else {
+        subscriber.borrow_mut().new_message(&message::Publish { qos: qos, ..publish.clone() });
+    }
+}
+
+//whether a topic name that was published to matches a subscription's filter
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_parts = filter.split("/");
+    let mut topic_parts = topic.split("/");
+    loop {
+        match (filter_parts.next(), topic_parts.next()) {
+            (Some("#"), _) => return true, //so that "finance/#" matches "finance"
+            (Some("+"), Some(_)) => {}
+            (Some(filter_part), Some(topic_part)) if filter_part == topic_part => {}
+            (None, None) => return true,
+            _ => return false,
+        }
     }
 }
 
@@ -49,39 +78,91 @@ impl<T: Subscriber> Node<T> {
         Node { children: HashMap::new(), leaves: vec![] }
     }
 
+    //subscribing to the same topic again replaces the old subscription
     fn add_subscription(&mut self, subscription: Subscription<T>) {
+        for leaf in self.leaves.iter_mut() {
+            if is_same_subscriber(leaf.subscriber.clone(), subscription.subscriber.clone()) {
+                leaf.qos = subscription.qos;
+                return;
+            }
+        }
         self.leaves.push(subscription);
     }
 }
 
 impl<T: Subscriber> Broker<T> {
     pub fn new(use_cache: bool) -> Self {
-        Broker { tree: Node::new(), use_cache: use_cache, cache: HashMap::new() }
+        Broker { tree: Node::new(), use_cache: use_cache, cache: HashMap::new(), retained: HashMap::new() }
     }
 
-    pub fn subscribe(&mut self, subscriber: Rc<RefCell<T>>, topic: &str) {
+    //returns false if the topic filter isn't valid, in which case nothing happens
+    pub fn subscribe(&mut self, subscriber: Rc<RefCell<T>>, topic: &str, qos: u8) -> bool {
+        if !message::valid_topic_filter(topic) {
+            return false;
+        }
+
         self.invalidate_cache();
         let sub_parts : Vec<&str> = topic.split("/").collect();
         Self::ensure_node_exists(&sub_parts, &mut self.tree);
-        Self::add_subscription_to_node(&mut self.tree, subscriber.clone(), &sub_parts, topic);
+        Self::add_subscription_to_node(&mut self.tree, subscriber.clone(), &sub_parts, topic, qos);
+
+        for retained in self.retained.values() {
+            if topic_matches(topic, &retained.topic) {
+                deliver(&subscriber, retained, qos);
+            }
+        }
+
+        true
     }
 
     pub fn unsubscribe_all(&mut self, subscriber: Rc<RefCell<T>>) {
         self.invalidate_cache();
-        Self::unsubscribe_impl(&mut self.tree, subscriber.clone(), &[], false);
+        Self::unsubscribe_impl(&mut self.tree, subscriber.clone(), &[], false, &mut vec![]);
     }
 
-    pub fn unsubscribe(&mut self, subscriber: Rc<RefCell<T>>, topics: &[&str]) {
+    //returns whether there was a subscription to remove for each of the topics
+    pub fn unsubscribe(&mut self, subscriber: Rc<RefCell<T>>, topics: &[&str]) -> Vec<bool> {
         self.invalidate_cache();
-        Self::unsubscribe_impl(&mut self.tree, subscriber.clone(), topics, true);
+        let mut removed = vec![];
+        Self::unsubscribe_impl(&mut self.tree, subscriber.clone(), topics, true, &mut removed);
+        topics.iter().map(|t| removed.iter().any(|r| r == t)).collect()
     }
 
-    pub fn publish(&mut self, topic: &str, payload: &[u8]) {
+    pub fn publish(&mut self, publish: &message::Publish) {
+        //wildcards in the topic would match subscriptions they have no business matching
+        if !message::valid_topic_name(&publish.topic) {
+            return;
+        }
+
+        //subscription identifiers belong to the subscriptions a message is delivered
+        //through, not to whoever published it
+        let publish = &message::Publish {
+            properties: publish.properties.iter().filter(|p| match **p {
+                message::Property::SubscriptionIdentifier(_) => false,
+                _ => true,
+            }).cloned().collect(),
+            ..publish.clone()
+        };
+
+        if publish.retain {
+            //an empty payload is how to get rid of a retained message
+            if publish.payload.is_empty() {
+                self.retained.remove(&publish.topic);
+            } else {
+                let retained = message::Publish { dup: false, packet_id: None, ..publish.clone() };
+                self.retained.insert(publish.topic.clone(), retained);
+            }
+        }
+
+        //DUP and the packet identifier only concern the publisher's side and RETAIN
+        //is only ever set when delivering a stored message to a new subscription
+        let publish = &message::Publish { dup: false, retain: false, packet_id: None, ..publish.clone() };
+        let topic = &publish.topic[..];
 
         if self.use_cache {
             if let Some(subscribers) = self.cache.get(topic) {
-                for subscriber in subscribers {
-                    subscriber.borrow_mut().new_message(payload);
+                for &(ref subscriber, qos) in subscribers {
+                    deliver(subscriber, publish, qos);
                 }
                 return;
             }
@@ -89,7 +170,7 @@ impl<T: Subscriber> Broker<T> {
 
 
         let pub_parts : Vec<&str> = topic.split("/").collect();
-        Self::publish_impl(&self.tree, &pub_parts, &payload, topic, self.use_cache, &mut self.cache);
+        Self::publish_impl(&self.tree, &pub_parts, publish, self.use_cache, &mut self.cache);
     }
 
     fn ensure_node_exists(sub_parts: &[&str], node: &mut Node<T>) {
@@ -106,7 +187,7 @@ impl<T: Subscriber> Broker<T> {
                                  .expect(&format!("Could not get node at {}", &part)));
     }
 
-    fn add_subscription_to_node(tree: &mut Node<T>, subscriber: Rc<RefCell<T>>, sub_parts: &[&str], topic: &str) {
+    fn add_subscription_to_node(tree: &mut Node<T>, subscriber: Rc<RefCell<T>>, sub_parts: &[&str], topic: &str, qos: u8) {
         if sub_parts.len() < 1 {
             panic!("oops");
         }
@@ -117,13 +198,13 @@ impl<T: Subscriber> Broker<T> {
         let sub_parts = &sub_parts[1..];
 
         if sub_parts.len() == 0 {
-            node.add_subscription(Subscription::new(subscriber.clone(), topic));
+            node.add_subscription(Subscription::new(subscriber.clone(), topic, qos));
         } else {
-            Self::add_subscription_to_node(node, subscriber, sub_parts, topic);
+            Self::add_subscription_to_node(node, subscriber, sub_parts, topic, qos);
         }
     }
 
-    fn publish_impl(tree: &Node<T>, pub_parts: &[&str], payload: &[u8], topic: &str, use_cache: bool, cache: &mut HashMap<String, Vec<Rc<RefCell<T>>>>) {
+    fn publish_impl(tree: &Node<T>, pub_parts: &[&str], publish: &message::Publish, use_cache: bool, cache: &mut HashMap<String, Vec<(Rc<RefCell<T>>, u8)>>) {
         if pub_parts.len() < 1 {
             return;
         }
@@ -135,41 +216,46 @@ impl<T: Subscriber> Broker<T> {
 
             if let Some(node) = tree.children.get(part) {
                 if pub_parts.len() == 0 || part == "#" {
-                    Self::publish_node(&node, payload, topic, use_cache, cache);
+                    Self::publish_node(&node, publish, use_cache, cache);
                 }
 
                 //so that "finance/#" matches "finance"
                 if pub_parts.len() == 0 && node.children.contains_key("#") {
                     Self::publish_node(node.children.get("#")
                                        .expect(&format!("Could not get node at {}", &part)),
-                                       payload, topic, use_cache, cache);
+                                       publish, use_cache, cache);
                 }
 
-                Self::publish_impl(&node, pub_parts, payload, topic, use_cache, cache);
+                Self::publish_impl(&node, pub_parts, publish, use_cache, cache);
             }
         }
     }
 
-    fn publish_node(node: &Node<T>, payload: &[u8], topic: &str, use_cache: bool, cache: &mut HashMap<String, Vec<Rc<RefCell<T>>>>) {
+    fn publish_node(node: &Node<T>, publish: &message::Publish, use_cache: bool, cache: &mut HashMap<String, Vec<(Rc<RefCell<T>>, u8)>>) {
+        let topic = &publish.topic[..];
         for subscription in &node.leaves {
             let subscriber = subscription.subscriber.clone();
-            subscriber.borrow_mut().new_message(payload);
+            deliver(&subscriber, publish, subscription.qos);
             if use_cache {
                 if !cache.contains_key(topic) {
                     cache.insert(topic.to_string(), vec![]);
                 }
                 if let Some(subscribers) = cache.get_mut(topic) {
-                    subscribers.push(subscriber.clone());
+                    subscribers.push((subscriber.clone(), subscription.qos));
                 }
             }
         }
     }
 
-    fn unsubscribe_impl(tree: &mut Node<T>, subscriber: Rc<RefCell<T>>, topics: &[&str], check_topics: bool) {
+    fn unsubscribe_impl(tree: &mut Node<T>, subscriber: Rc<RefCell<T>>, topics: &[&str], check_topics: bool,
+                        removed: &mut Vec<String>) {
         tree.leaves.retain(|s| {
             let is_same_subscriber = is_same_subscriber(s.subscriber.clone(), subscriber.clone());
             //I have no idea why t below is &&&str, I added a tripe deref cos the compiler told me to
             let is_same_topic = !check_topics || topics.into_iter().find(|t| ***t == s.topic).is_some();
+            if is_same_subscriber && is_same_topic {
+                removed.push(s.topic.clone());
+            }
             !is_same_subscriber || !is_same_topic
         });
 
@@ -178,7 +264,7 @@ impl<T: Subscriber> Broker<T> {
         }
 
         for (_, node) in tree.children.iter_mut() {
-            Self::unsubscribe_impl(node, subscriber.clone(), topics, check_topics);
+            Self::unsubscribe_impl(node, subscriber.clone(), topics, check_topics, removed);
         }
     }
 
@@ -191,40 +277,47 @@ impl<T: Subscriber> Broker<T> {
 
 #[cfg(test)]
 struct TestSubscriber {
-    msgs: Vec<Vec<u8>>,
+    msgs: Vec<Vec<u8>>, //the payloads
+    publishes: Vec<message::Publish>,
 }
 
 #[cfg(test)]
 impl TestSubscriber {
     fn new() -> Self {
-        TestSubscriber{msgs: vec![]}
+        TestSubscriber{msgs: vec![], publishes: vec![]}
     }
 }
 
 #[cfg(test)]
 impl Subscriber for TestSubscriber {
-    fn new_message(&mut self, bytes: &[u8]) {
-        self.msgs.push(bytes.to_vec());
+    fn new_message(&mut self, publish: &message::Publish) {
+        self.msgs.push(publish.payload.clone());
+        self.publishes.push(publish.clone());
     }
 }
 
+#[cfg(test)]
+fn publish<T: Subscriber>(broker: &mut Broker<T>, topic: &str, payload: &[u8]) {
+    broker.publish(&message::Publish::new(topic, payload));
+}
+
 #[test]
 fn test_subscribe() {
     let mut broker = Broker::<TestSubscriber>::new(false);
     let sub_rc = Rc::new(RefCell::new(TestSubscriber::new()));
     let subscriber = sub_rc.clone();
-    broker.publish("topics/foo", &[0, 1, 2]);
+    publish(&mut broker, "topics/foo", &[0, 1, 2]);
     assert_eq!(subscriber.borrow().msgs.len(), 0);
 
-    broker.subscribe(subscriber.clone(), "topics/foo");
-    broker.publish("topics/foo", &[0, 1, 9]); //should get this
-    broker.publish("topics/bar", &[2, 4, 6]); //shouldn't get this
+    broker.subscribe(subscriber.clone(), "topics/foo", 0);
+    publish(&mut broker, "topics/foo", &[0, 1, 9]); //should get this
+    publish(&mut broker, "topics/bar", &[2, 4, 6]); //shouldn't get this
     assert_eq!(subscriber.borrow().msgs.len(), 1);
     assert_eq!(subscriber.borrow().msgs[0], &[0, 1, 9]);
 
-    broker.subscribe(subscriber.clone(), "topics/bar");
-    broker.publish("topics/foo", &[1, 3, 5, 7]);
-    broker.publish("topics/bar", &[2, 4]);
+    broker.subscribe(subscriber.clone(), "topics/bar", 0);
+    publish(&mut broker, "topics/foo", &[1, 3, 5, 7]);
+    publish(&mut broker, "topics/bar", &[2, 4]);
     assert_eq!(subscriber.borrow().msgs.len(), 3);
     assert_eq!(subscriber.borrow().msgs[0], &[0, 1, 9]);
     assert_eq!(subscriber.borrow().msgs[1], &[1, 3, 5, 7]);
@@ -238,16 +331,16 @@ fn test_unsubscribe_all() {
     let sub_rc = Rc::new(RefCell::new(TestSubscriber::new()));
     let subscriber = sub_rc.clone();
 
-    broker.subscribe(subscriber.clone(), "topics/foo");
-    broker.publish("topics/foo", &[0, 1, 9]); //should get this
-    broker.publish("topics/bar", &[2, 4, 6]); //shouldn't get this
+    broker.subscribe(subscriber.clone(), "topics/foo", 0);
+    publish(&mut broker, "topics/foo", &[0, 1, 9]); //should get this
+    publish(&mut broker, "topics/bar", &[2, 4, 6]); //shouldn't get this
     assert_eq!(subscriber.borrow().msgs.len(), 1);
     assert_eq!(subscriber.borrow().msgs[0], &[0, 1, 9]);
 
     broker.unsubscribe_all(subscriber.clone());
-    broker.publish("topics/foo", &[0, 1, 9]);
-    broker.publish("topics/bar", &[2, 4]);
-    broker.publish("topics/baz", &[2, 4, 7, 11]);
+    publish(&mut broker, "topics/foo", &[0, 1, 9]);
+    publish(&mut broker, "topics/bar", &[2, 4]);
+    publish(&mut broker, "topics/baz", &[2, 4, 7, 11]);
 
     //shouldn't have changed
     assert_eq!(subscriber.borrow().msgs.len(), 1);
@@ -260,19 +353,19 @@ fn test_unsubscribe_one() {
     let sub_rc = Rc::new(RefCell::new(TestSubscriber::new()));
     let subscriber = sub_rc.clone();
 
-    broker.subscribe(subscriber.clone(), "topics/foo");
-    broker.subscribe(subscriber.clone(), "topics/bar");
-    broker.publish("topics/foo", &[0, 1, 9]); //should get this
-    broker.publish("topics/bar", &[2, 4]); //should get this
-    broker.publish("topics/baz", &[2, 4, 7, 11]); //shouldn't get this
+    broker.subscribe(subscriber.clone(), "topics/foo", 0);
+    broker.subscribe(subscriber.clone(), "topics/bar", 0);
+    publish(&mut broker, "topics/foo", &[0, 1, 9]); //should get this
+    publish(&mut broker, "topics/bar", &[2, 4]); //should get this
+    publish(&mut broker, "topics/baz", &[2, 4, 7, 11]); //shouldn't get this
     assert_eq!(subscriber.borrow().msgs.len(), 2);
     assert_eq!(subscriber.borrow().msgs[0], &[0, 1, 9]);
     assert_eq!(subscriber.borrow().msgs[1], &[2, 4]);
 
-    broker.unsubscribe(subscriber.clone(), &["topics/foo"]);
-    broker.publish("topics/foo", &[0, 1, 9]); //shouldn't get this
-    broker.publish("topics/bar", &[2, 4]); //should get this
-    broker.publish("topics/baz", &[2, 4, 7, 11]); //shouldn't get this
+    assert_eq!(broker.unsubscribe(subscriber.clone(), &["topics/foo", "topics/baz"]), vec![true, false]);
+    publish(&mut broker, "topics/foo", &[0, 1, 9]); //shouldn't get this
+    publish(&mut broker, "topics/bar", &[2, 4]); //should get this
+    publish(&mut broker, "topics/baz", &[2, 4, 7, 11]); //shouldn't get this
 
     assert_eq!(subscriber.borrow().msgs.len(), 3);
     assert_eq!(subscriber.borrow().msgs[0], &[0, 1, 9]);
@@ -287,8 +380,8 @@ fn test_matches(pub_topic: &str, sub_topic: &str) -> bool {
     let sub_rc = Rc::new(RefCell::new(TestSubscriber::new()));
     let subscriber = sub_rc.clone();
 
-    broker.subscribe(subscriber.clone(), sub_topic);
-    broker.publish(pub_topic, &[0, 1, 2]);
+    broker.subscribe(subscriber.clone(), sub_topic, 0);
+    publish(&mut broker, pub_topic, &[0, 1, 2]);
     let subscriber = subscriber.borrow();
     subscriber.msgs.len() == 1
 }
@@ -325,28 +418,246 @@ fn test_subscribe_wildcards() {
     let subscriber3 = sub_rc3.clone();
     let subscriber4 = sub_rc4.clone();
 
-    broker.subscribe(subscriber1.clone(), "topics/foo/+");
-    broker.publish("topics/foo/bar", &[3]);
-    broker.publish("topics/bar/baz/boo", &[4]); //shouldn't get this one
+    broker.subscribe(subscriber1.clone(), "topics/foo/+", 0);
+    publish(&mut broker, "topics/foo/bar", &[3]);
+    publish(&mut broker, "topics/bar/baz/boo", &[4]); //shouldn't get this one
     assert_eq!(subscriber1.borrow().msgs, vec![&[3]]);
 
-    broker.subscribe(subscriber2.clone(), "topics/foo/#");
-    broker.publish("topics/foo/bar", &[3]);
-    broker.publish("topics/bar/baz/boo", &[4]); //shouldn't get this one
+    broker.subscribe(subscriber2.clone(), "topics/foo/#", 0);
+    publish(&mut broker, "topics/foo/bar", &[3]);
+    publish(&mut broker, "topics/bar/baz/boo", &[4]); //shouldn't get this one
     assert_eq!(subscriber1.borrow().msgs, vec![&[3], &[3]]);
     assert_eq!(subscriber2.borrow().msgs, vec![&[3]]);
 
-    broker.subscribe(subscriber3.clone(), "topics/+/bar");
-    broker.subscribe(subscriber4.clone(), "topics/#");
+    broker.subscribe(subscriber3.clone(), "topics/+/bar", 0);
+    broker.subscribe(subscriber4.clone(), "topics/#", 0);
 
-    broker.publish("topics/foo/bar", &[3]);
-    broker.publish("topics/bar/baz/boo", &[4]);
-    broker.publish("topics/boo/bar/zoo", &[5]);
-    broker.publish("topics/foo/bar/zoo", &[6]);
-    broker.publish("topics/bbobobobo/bar", &[7]);
+    publish(&mut broker, "topics/foo/bar", &[3]);
+    publish(&mut broker, "topics/bar/baz/boo", &[4]);
+    publish(&mut broker, "topics/boo/bar/zoo", &[5]);
+    publish(&mut broker, "topics/foo/bar/zoo", &[6]);
+    publish(&mut broker, "topics/bbobobobo/bar", &[7]);
 
     assert_eq!(subscriber1.borrow().msgs, vec![&[3], &[3], &[3]]);
     assert_eq!(subscriber2.borrow().msgs, vec![&[3], &[3], &[6]]);
     assert_eq!(subscriber3.borrow().msgs, vec![&[3], &[7]]);
     assert_eq!(subscriber4.borrow().msgs, vec![&[3], &[4], &[5], &[6], &[7]]);
 }
+
+#[test]
+fn test_publish_flags() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber.clone(), "topics/foo", 1);
+
+    broker.publish(&message::Publish {
+        dup: true,
+        qos: 1,
+        retain: true,
+        topic: "topics/foo".to_string(),
+        packet_id: Some(3),
+        payload: vec![1, 2, 3],
+        properties: vec![],
+    });
+
+    let subscriber = subscriber.borrow();
+    assert_eq!(subscriber.publishes.len(), 1);
+    assert_eq!(subscriber.publishes[0].dup, false);
+    assert_eq!(subscriber.publishes[0].retain, false);
+    assert_eq!(subscriber.publishes[0].qos, 1);
+    assert_eq!(subscriber.publishes[0].payload, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_invalid_topics() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+
+    assert_eq!(broker.subscribe(subscriber.clone(), "finance#", 0), false);
+    assert_eq!(broker.subscribe(subscriber.clone(), "finance/#/stock", 0), false);
+    assert_eq!(broker.subscribe(subscriber.clone(), "", 0), false);
+    assert_eq!(broker.subscribe(subscriber.clone(), "finance/+", 0), true);
+
+    publish(&mut broker, "finance/+", &[1]);
+    publish(&mut broker, "finance/#", &[2]);
+    assert_eq!(subscriber.borrow().msgs.len(), 0);
+
+    publish(&mut broker, "finance/stock", &[3]);
+    assert_eq!(subscriber.borrow().msgs, vec![&[3]]);
+}
+
+#[test]
+fn test_topic_matches() {
+    assert_eq!(topic_matches("foo/bar/baz", "foo/bar/baz"), true);
+    assert_eq!(topic_matches("foo/+", "foo/bar"), true);
+    assert_eq!(topic_matches("foo/+", "foo/bar/baz"), false);
+    assert_eq!(topic_matches("foo/#", "foo/bar/baz"), true);
+    assert_eq!(topic_matches("finance/#", "finance"), true);
+    assert_eq!(topic_matches("#", "finance"), true);
+    assert_eq!(topic_matches("foo/+/bar/baz/#", "foo/bla/bar/baz/boo/bogadog"), true);
+    assert_eq!(topic_matches("finance/stock/ibm", "finance/stock"), false);
+    assert_eq!(topic_matches("finance/stock", "finance/stock/ibm"), false);
+    assert_eq!(topic_matches("+/+", "/finance"), true);
+    assert_eq!(topic_matches("+", "/finance"), false);
+}
+
+#[cfg(test)]
+fn publish_retained<T: Subscriber>(broker: &mut Broker<T>, topic: &str, payload: &[u8]) {
+    broker.publish(&message::Publish { retain: true, ..message::Publish::new(topic, payload) });
+}
+
+#[test]
+fn test_retained() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    let subscriber1 = Rc::new(RefCell::new(TestSubscriber::new()));
+    let subscriber2 = Rc::new(RefCell::new(TestSubscriber::new()));
+
+    broker.subscribe(subscriber1.clone(), "topics/foo", 0);
+    publish_retained(&mut broker, "topics/foo", &[1]);
+    publish_retained(&mut broker, "topics/foo", &[2]); //replaces the one before
+    publish(&mut broker, "topics/foo", &[3]); //not retained
+    publish_retained(&mut broker, "topics/bar", &[4]);
+
+    //current subscribers get them as normal messages
+    assert_eq!(subscriber1.borrow().msgs, vec![&[1], &[2], &[3]]);
+    assert_eq!(subscriber1.borrow().publishes.iter().any(|p| p.retain), false);
+
+    broker.subscribe(subscriber2.clone(), "topics/foo", 0);
+    assert_eq!(subscriber2.borrow().msgs, vec![&[2]]);
+    assert_eq!(subscriber2.borrow().publishes[0].retain, true);
+    assert_eq!(subscriber2.borrow().publishes[0].topic, "topics/foo");
+
+    //the old subscriber doesn't get it again
+    assert_eq!(subscriber1.borrow().msgs.len(), 3);
+}
+
+#[test]
+fn test_retained_wildcards() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    publish_retained(&mut broker, "topics/foo", &[1]);
+    publish_retained(&mut broker, "topics/bar", &[2]);
+    publish_retained(&mut broker, "topics/foo/bar", &[3]);
+    publish_retained(&mut broker, "other", &[4]);
+
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber.clone(), "topics/+", 0);
+    let mut msgs = subscriber.borrow().msgs.clone();
+    msgs.sort();
+    assert_eq!(msgs, vec![vec![1], vec![2]]);
+
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber.clone(), "topics/#", 0);
+    let mut msgs = subscriber.borrow().msgs.clone();
+    msgs.sort();
+    assert_eq!(msgs, vec![vec![1], vec![2], vec![3]]);
+
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber.clone(), "#", 0);
+    assert_eq!(subscriber.borrow().msgs.len(), 4);
+    assert_eq!(subscriber.borrow().publishes.iter().all(|p| p.retain), true);
+}
+
+#[test]
+fn test_retained_cleared() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    let subscriber1 = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber1.clone(), "topics/foo", 0);
+
+    publish_retained(&mut broker, "topics/foo", &[1]);
+    publish_retained(&mut broker, "topics/foo", &[]);
+    //current subscribers still get the empty one
+    assert_eq!(subscriber1.borrow().msgs, vec![vec![1], vec![]]);
+
+    let subscriber2 = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber2.clone(), "topics/foo", 0);
+    assert_eq!(subscriber2.borrow().msgs.len(), 0);
+}
+
+#[cfg(test)]
+fn publish_qos<T: Subscriber>(broker: &mut Broker<T>, topic: &str, qos: u8) {
+    broker.publish(&message::Publish {
+        qos: qos,
+        packet_id: if qos > 0 { Some(42) } else { None },
+        ..message::Publish::new(topic, &[qos])
+    });
+}
+
+#[test]
+fn test_qos_downgrade() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    let subscriber0 = Rc::new(RefCell::new(TestSubscriber::new()));
+    let subscriber1 = Rc::new(RefCell::new(TestSubscriber::new()));
+    let subscriber2 = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber0.clone(), "topics/foo", 0);
+    broker.subscribe(subscriber1.clone(), "topics/+", 1);
+    broker.subscribe(subscriber2.clone(), "topics/#", 2);
+
+    for qos in 0 .. 3 {
+        publish_qos(&mut broker, "topics/foo", qos);
+    }
+
+    let qos = |subscriber: &Rc<RefCell<TestSubscriber>>| -> Vec<u8> {
+        subscriber.borrow().publishes.iter().map(|p| p.qos).collect()
+    };
+    assert_eq!(qos(&subscriber0), vec![0, 0, 0]);
+    assert_eq!(qos(&subscriber1), vec![0, 1, 1]);
+    assert_eq!(qos(&subscriber2), vec![0, 1, 2]);
+
+    //packet identifiers belong to the publisher's connection
+    assert_eq!(subscriber2.borrow().publishes.iter().all(|p| p.packet_id.is_none()), true);
+}
+
+#[test]
+fn test_publish_drops_subscription_identifiers() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber.clone(), "topics/foo", 0);
+
+    let user_property = message::Property::UserProperty("k".to_string(), "v".to_string());
+    broker.publish(&message::Publish {
+        retain: true,
+        properties: vec![message::Property::SubscriptionIdentifier(7), user_property.clone()],
+        ..message::Publish::new("topics/foo", &[1])
+    });
+    assert_eq!(subscriber.borrow().publishes[0].properties, vec![user_property.clone()]);
+
+    let late = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(late.clone(), "topics/foo", 0);
+    assert_eq!(late.borrow().publishes[0].properties, vec![user_property]);
+}
+
+#[test]
+fn test_qos_downgrade_cached() {
+    let mut broker = Broker::<TestSubscriber>::new(true);
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber.clone(), "topics/foo", 1);
+
+    publish_qos(&mut broker, "topics/foo", 2);
+    publish_qos(&mut broker, "topics/foo", 2); //from the cache this time
+    let qos: Vec<u8> = subscriber.borrow().publishes.iter().map(|p| p.qos).collect();
+    assert_eq!(qos, vec![1, 1]);
+}
+
+#[test]
+fn test_resubscribe_replaces_qos() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber.clone(), "topics/foo", 0);
+    broker.subscribe(subscriber.clone(), "topics/foo", 2);
+
+    publish_qos(&mut broker, "topics/foo", 2);
+    assert_eq!(subscriber.borrow().publishes.len(), 1);
+    assert_eq!(subscriber.borrow().publishes[0].qos, 2);
+}
+
+#[test]
+fn test_retained_qos() {
+    let mut broker = Broker::<TestSubscriber>::new(false);
+    broker.publish(&message::Publish { qos: 2, packet_id: Some(3), retain: true,
+                                       ..message::Publish::new("topics/foo", &[1]) });
+
+    let subscriber = Rc::new(RefCell::new(TestSubscriber::new()));
+    broker.subscribe(subscriber.clone(), "topics/foo", 1);
+    assert_eq!(subscriber.borrow().publishes[0].qos, 1);
+    assert_eq!(subscriber.borrow().publishes[0].packet_id, None);
+}
@@ -2,8 +2,10 @@ extern crate mio;
 
 use std::io::Write;
 use std::io::Read;
+use std::io::ErrorKind;
 use std::rc::{Rc};
 use std::cell::{RefCell};
+use std::time::Instant;
 use mio::tcp::*;
 mod server;
 mod broker;
@@ -11,13 +13,46 @@ mod message;
 
 
 const MQTT_SERVER_TOKEN: mio::Token = mio::Token(0);
+const READ_BUFFER_SIZE: usize = 1024 * 64; //shared by all connections
+const TICK_MS: u64 = 1000; //how often the server gets to do time-based work
+const READS_PER_EVENT: usize = 16; //so one busy client can't starve the others
+const DEFAULT_MAX_OUTBOUND: usize = 1024 * 1024; //bytes queued for a client before giving up on it
 
 fn main() {
     let address = "0.0.0.0:1883".parse().unwrap();
     let listener = TcpListener::bind(&address).expect(&format!("Could not bind to {}", address));
     let mut event_loop = mio::EventLoop::new().expect("Could not create MIO event loop");
     event_loop.register(&listener, MQTT_SERVER_TOKEN).expect("Could not register listener");
-    event_loop.run(&mut MioHandler::new(listener, std::env::args().len() > 1)).expect("Could not run event loop");
+    let max_packet_size = std::env::var("MQTT_MAX_PACKET_SIZE").ok()
+        .map(|size| size.parse().expect("MQTT_MAX_PACKET_SIZE is not a number"))
+        .unwrap_or(server::DEFAULT_MAX_PACKET_SIZE);
+    let max_outbound = std::env::var("MQTT_MAX_OUTBOUND").ok()
+        .map(|size| size.parse().expect("MQTT_MAX_OUTBOUND is not a number"))
+        .unwrap_or(DEFAULT_MAX_OUTBOUND);
+    let mut handler = MioHandler::new(listener, std::env::args().len() > 1, max_packet_size, max_outbound);
+    if std::env::var("MQTT_REJECT_NON_CHARACTERS").is_ok() {
+        handler.server.reject_non_characters();
+    }
+    if let (Ok(username), Ok(password)) = (std::env::var("MQTT_USERNAME"), std::env::var("MQTT_PASSWORD")) {
+        handler.server.require_credentials(&username, password.as_bytes());
+    }
+    if let Ok(max_clients) = std::env::var("MQTT_MAX_CLIENTS") {
+        handler.server.max_clients(max_clients.parse().expect("MQTT_MAX_CLIENTS is not a number"));
+    }
+    if let Ok(seconds) = std::env::var("MQTT_CONNECT_TIMEOUT") {
+        handler.server.connect_timeout(seconds.parse().expect("MQTT_CONNECT_TIMEOUT is not a number"));
+    }
+    if let Ok(max_queued) = std::env::var("MQTT_MAX_QUEUED") {
+        handler.server.max_queued(max_queued.parse().expect("MQTT_MAX_QUEUED is not a number"));
+    }
+    if let Ok(seconds) = std::env::var("MQTT_SESSION_EXPIRY") {
+        handler.server.session_expiry(seconds.parse().expect("MQTT_SESSION_EXPIRY is not a number"));
+    }
+    if let Ok(seconds) = std::env::var("MQTT_SERVER_KEEP_ALIVE") {
+        handler.server.override_keep_alive(seconds.parse().expect("MQTT_SERVER_KEEP_ALIVE is not a number"));
+    }
+    event_loop.timeout_ms((), TICK_MS).expect("Could not schedule timeout");
+    event_loop.run(&mut handler).expect("Could not run event loop");
 }
 
 
@@ -26,14 +61,26 @@ struct MioHandler {
     connections: mio::util::Slab<Rc<RefCell<Connection>>>,
     mqtt_streams: mio::util::Slab<server::Stream>,
     server: server::Server<Connection>,
+    read_buffer: Vec<u8>,
+    max_packet_size: usize,
+    max_outbound: usize,
+    changed: Rc<RefCell<Vec<mio::Token>>>, //connections that need registering again or dropping
 }
 
+//writes to the socket never block: what it won't take right away waits in
+//outbound until the socket says it's writable again
 struct Connection {
     socket: mio::tcp::TcpStream,
+    token: mio::Token,
+    outbound: Vec<u8>,
+    max_outbound: usize,
+    dropped: bool, //can't keep up or the socket's broken
+    closing: bool, //the server's done with it, as soon as outbound is written
+    changed: Rc<RefCell<Vec<mio::Token>>>,
 }
 
 impl MioHandler {
-    fn new(listener: TcpListener, use_cache: bool) -> Self {
+    fn new(listener: TcpListener, use_cache: bool, max_packet_size: usize, max_outbound: usize) -> Self {
 
         if use_cache {
             println!("Enabling the cache");
@@ -48,6 +95,55 @@ impl MioHandler {
             connections: connections_slab,
             mqtt_streams: mqtt_stream_slab,
             server: server::Server::new(use_cache),
+            read_buffer: vec![0; READ_BUFFER_SIZE],
+            max_packet_size: max_packet_size,
+            max_outbound: max_outbound,
+            changed: Rc::new(RefCell::new(vec![])),
+        }
+    }
+
+    fn disconnect(&mut self, event_loop: &mut mio::EventLoop<MioHandler>, token: mio::Token) {
+        let connection = self.connections[token].clone();
+        event_loop.deregister(&connection.borrow().socket)
+            .expect("Could not deregister connection with event loop");
+        self.server.client_disconnected(connection.clone());
+        connection.borrow_mut().flush(); //last chance for whatever's still queued
+        self.connections.remove(token).expect("Could not remove connection from slab");
+        self.mqtt_streams.remove(token).expect("Could not remove stream from slab");
+    }
+
+    //sending to one client happens while handling another's events, so it's only
+    //afterwards that connections can be registered for writing or dropped
+    fn update_connections(&mut self, event_loop: &mut mio::EventLoop<MioHandler>) {
+        loop {
+            let tokens: Vec<mio::Token> = self.changed.borrow_mut().drain(..).collect();
+            if tokens.is_empty() {
+                break;
+            }
+
+            for token in tokens {
+                let connection = match self.connections.get(token) {
+                    Some(connection) => connection.clone(),
+                    None => continue, //already gone
+                };
+
+                let (dropped, closing, pending) = {
+                    let connection = connection.borrow();
+                    (connection.dropped, connection.closing, !connection.outbound.is_empty())
+                };
+
+                if dropped || (closing && !pending) {
+                    self.disconnect(event_loop, token);
+                } else {
+                    let interest = if pending {
+                        mio::EventSet::readable() | mio::EventSet::writable()
+                    } else {
+                        mio::EventSet::readable()
+                    };
+                    event_loop.reregister(&connection.borrow().socket, token, interest, mio::PollOpt::edge())
+                        .expect("Could not reregister connection with event loop");
+                }
+            }
         }
     }
 }
@@ -72,13 +168,17 @@ impl mio::Handler for MioHandler {
                         //(though not really) and be passed as mutable borrow simultaneously
                         //to connection_ready
 
+                        let max_outbound = self.max_outbound;
+                        let changed = self.changed.clone();
                         let token = self.connections
-                            .insert_with(|_| Rc::new(RefCell::new(Connection::new(socket))))
+                            .insert_with(|token| Rc::new(RefCell::new(Connection::new(socket, token, max_outbound, changed))))
                             .expect("Could not insert new connection in slab");
+                        let max_packet_size = self.max_packet_size;
                         self.mqtt_streams.
-                            insert_with(|_| server::Stream::new())
+                            insert_with(|_| server::Stream::new(max_packet_size))
                             .expect("Could not insert new stream into slab");
                         let connection = &self.connections[token].clone();
+                        self.server.client_connected(connection.clone());
                         event_loop.register_opt(
                             &connection.borrow().socket,
                             token,
@@ -95,56 +195,143 @@ impl mio::Handler for MioHandler {
                 }
             }
             _ => {
-                assert!(events.is_readable());
-                let still_connected = connection_ready(&mut self.server,
+                if self.connections.get(token).is_none() {
+                    return; //dropped while handling an earlier event
+                }
+
+                let mut still_connected = true;
+                if events.is_writable() {
+                    let mut connection = self.connections[token].borrow_mut();
+                    still_connected = connection.flush();
+                    connection.changed(); //might not need writable any more
+                }
+                //a hang up or error without readable still needs a read to find out about it
+                if still_connected && (events.is_readable() || events.is_hup() || events.is_error()) {
+                    still_connected = connection_ready(&mut self.server,
                                                        &mut self.mqtt_streams[token],
-                                                       self.connections[token].clone());
+                                                       self.connections[token].clone(),
+                                                       &mut self.read_buffer);
+                }
                 if !still_connected {
-                    event_loop.deregister(&self.connections[token].borrow().socket)
-                        .expect("Could not deregister connection with event loop");
-                    self.server.unsubscribe_all(self.connections[token].clone());
-                    self.connections[token].borrow_mut().socket.flush().expect("Could not flush socket");
-                    self.connections.remove(token).expect("Could not remove connection from slab");
+                    self.disconnect(event_loop, token);
                 }
             }
         }
+
+        self.update_connections(event_loop);
+    }
+
+    fn timeout(&mut self, event_loop: &mut mio::EventLoop<MioHandler>, _: ()) {
+        self.server.tick(Instant::now());
+        self.update_connections(event_loop);
+        event_loop.timeout_ms((), TICK_MS).expect("Could not schedule timeout");
     }
 }
 
+//with edge-triggered readiness there won't be another event until more data
+//arrives, so the socket has to be read until it would block. If the budget
+//runs out first the connection gets re-armed to come back to what's left.
 fn connection_ready(server: &mut server::Server<Connection>,
                     stream: &mut server::Stream,
-                    connection: Rc<RefCell<Connection>>) -> bool {
-    let connection = connection.clone();
-    let read_result = connection.borrow_mut().read(stream.buffer());
-
-    match read_result {
-        Ok(length) => {
-            if length >= stream.total_buffer_len() {
-                panic!(format!("Too many bytes ({}) for puny stream buffer ({})",
-                               length, stream.total_buffer_len()));
+                    connection: Rc<RefCell<Connection>>,
+                    read_buffer: &mut [u8]) -> bool {
+    let mut reads = 0;
+    while reads < READS_PER_EVENT {
+        let read_result = connection.borrow_mut().read(read_buffer);
+
+        match read_result {
+            //the other end hung up, or we did. Without a DISCONNECT that's abnormal
+            //and the server deals with it (will and all) in client_disconnected
+            Ok(0) => return false,
+            Ok(length) => {
+                reads += 1;
+                if !stream.handle_messages(&read_buffer[.. length], server, connection.clone()) {
+                    return false;
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return true,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                println!("Error reading bytes from stream: {}", e);
+                return false;
             }
-            stream.handle_messages(length, server, connection.clone())
-        }
-        _ => {
-            println!("Error reading bytes from stream");
-            false
         }
     }
+
+    connection.borrow().changed(); //there may be more, reregistering gets us another event
+    true
 }
 
 
 impl Connection {
-    fn new(socket: mio::tcp::TcpStream) -> Self {
-        Connection { socket: socket }
+    fn new(socket: mio::tcp::TcpStream, token: mio::Token, max_outbound: usize,
+           changed: Rc<RefCell<Vec<mio::Token>>>) -> Self {
+        Connection {
+            socket: socket,
+            token: token,
+            outbound: vec![],
+            max_outbound: max_outbound,
+            dropped: false,
+            closing: false,
+            changed: changed,
+        }
     }
 
     fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
         self.socket.read(buffer)
     }
+
+    //writes as much of outbound as the socket will take, returns false if it's broken
+    fn flush(&mut self) -> bool {
+        while !self.outbound.is_empty() {
+            match self.socket.write(&self.outbound) {
+                Ok(0) => return false,
+                Ok(length) => {
+                    self.outbound.drain(.. length);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    println!("Error writing to socket: {}", e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    //let the event loop know this connection needs looking at
+    fn changed(&self) {
+        self.changed.borrow_mut().push(self.token);
+    }
 }
 
-impl broker::Subscriber for Connection {
-    fn new_message(&mut self, bytes: &[u8]) {
-        self.socket.write_all(bytes).expect("Error writing to socket");
+impl server::Client for Connection {
+    fn send(&mut self, bytes: &[u8]) {
+        if self.dropped {
+            return;
+        }
+
+        //if there's a queue already the socket isn't writable, no point trying
+        let was_empty = self.outbound.is_empty();
+        self.outbound.extend_from_slice(bytes);
+        if was_empty && !self.flush() {
+            self.dropped = true;
+        }
+
+        if self.outbound.len() > self.max_outbound {
+            println!("Client not keeping up with {} bytes queued, dropping it", self.outbound.len());
+            self.dropped = true;
+        }
+
+        if self.dropped || (was_empty && !self.outbound.is_empty()) {
+            self.changed();
+        }
+    }
+
+    //the event loop drops the connection once everything queued has been written
+    fn close(&mut self) {
+        self.closing = true;
+        self.changed();
     }
 }
@@ -1,30 +1,1915 @@
-use std::mem;
-
-const HEADER_LEN: usize = 2;
-
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum MqttType {
     //Reserved = 0,
     Connect = 1,
-    // ConnAck = 2,
+    ConnAck = 2,
     Publish = 3,
-    // PubAck = 4,
-    // PubRec = 5,
-    // PubRel = 6,
-    // PubComp = 7,
+    PubAck = 4,
+    PubRec = 5,
+    PubRel = 6,
+    PubComp = 7,
     Subscribe = 8,
     SubAck = 9,
-    // Unsubscribe = 0xa,
-    // UnsubAck = 0xb,
+    Unsubscribe = 0xa,
+    UnsubAck = 0xb,
     PingReq = 0xc,
-    // PingResp = 0xd,
+    PingResp = 0xd,
     Disconnect = 0xe,
+    Auth = 0xf, //MQTT 5 only
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DecodeError {
+    UnknownPacketType(u8),
+    Truncated,
+    InvalidUtf8,
+    MalformedRemainingLength,
+    ReservedFlagsSet,
+    InvalidConnectFlags,
+    UnacceptableProtocolVersion,
+    InvalidProperty(u8),
+    DuplicateProperty(u8),
+    InvalidPacketId,
+    InvalidQoS,
+    PacketTooLarge,
+    InvalidTopic,
+    DisallowedCharacter(char),
+    NoTopics,
+    InvalidReasonCode(u8),
+    InvalidSubscriptionOptions,
+}
+
+//the spec lets servers decide whether to put up with Unicode non-characters in strings
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum NonCharacters {
+    Allow,
+    Reject,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ProtocolVersion {
+    V31, //"MQIsdp", level 3
+    V311, //"MQTT", level 4
+    V5, //"MQTT", level 5
+}
+
+impl ProtocolVersion {
+    pub fn new(name: &str, level: u8) -> Option<Self> {
+        match (name, level) {
+            ("MQIsdp", 3) => Some(ProtocolVersion::V31),
+            ("MQTT", 4) => Some(ProtocolVersion::V311),
+            ("MQTT", 5) => Some(ProtocolVersion::V5),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ProtocolVersion::V31 => "MQIsdp",
+            ProtocolVersion::V311 | ProtocolVersion::V5 => "MQTT",
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        match *self {
+            ProtocolVersion::V31 => 3,
+            ProtocolVersion::V311 => 4,
+            ProtocolVersion::V5 => 5,
+        }
+    }
+}
+
+pub fn message_type(bytes: &[u8]) -> Result<MqttType, DecodeError> {
+    if bytes.len() < 1 {
+        return Err(DecodeError::Truncated);
+    }
+
+    match bytes[0] >> 4 {
+        1 => Ok(MqttType::Connect),
+        2 => Ok(MqttType::ConnAck),
+        3 => Ok(MqttType::Publish),
+        4 => Ok(MqttType::PubAck),
+        5 => Ok(MqttType::PubRec),
+        6 => Ok(MqttType::PubRel),
+        7 => Ok(MqttType::PubComp),
+        8 => Ok(MqttType::Subscribe),
+        9 => Ok(MqttType::SubAck),
+        0xa => Ok(MqttType::Unsubscribe),
+        0xb => Ok(MqttType::UnsubAck),
+        0xc => Ok(MqttType::PingReq),
+        0xd => Ok(MqttType::PingResp),
+        0xe => Ok(MqttType::Disconnect),
+        0xf => Ok(MqttType::Auth),
+        other => Err(DecodeError::UnknownPacketType(other)),
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck(ConnAck),
+    Publish(Publish),
+    PubAck(PubAck),
+    PubRec(PubRec),
+    PubRel(PubRel),
+    PubComp(PubComp),
+    Subscribe(Subscribe),
+    SubAck(SubAck),
+    Unsubscribe(Unsubscribe),
+    UnsubAck(UnsubAck),
+    PingReq(PingReq),
+    PingResp(PingResp),
+    Disconnect(Disconnect),
+    Auth(Auth),
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Connect {
+    pub version: ProtocolVersion,
+    pub clean_session: bool,
+    pub keep_alive: u16,
+    pub client_id: String,
+    pub will: Option<Will>,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Will {
+    pub topic: String,
+    pub message: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct ConnAck {
+    pub session_present: bool,
+    pub return_code: u8, //the reason code for MQTT 5
+    pub properties: Vec<Property>,
+}
+
+//why a connection was refused, each version has its own code for it
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ConnectError {
+    UnacceptableProtocolVersion,
+    IdentifierRejected,
+    ServerUnavailable,
+    BadUsernameOrPassword,
+    NotAuthorized,
+}
+
+impl ConnectError {
+    pub fn return_code(&self, version: ProtocolVersion) -> u8 {
+        let v5 = version == ProtocolVersion::V5;
+        match *self {
+            ConnectError::UnacceptableProtocolVersion => if v5 { 0x84 } else { 1 },
+            ConnectError::IdentifierRejected => if v5 { 0x85 } else { 2 },
+            ConnectError::ServerUnavailable => if v5 { 0x88 } else { 3 },
+            ConnectError::BadUsernameOrPassword => if v5 { 0x86 } else { 4 },
+            ConnectError::NotAuthorized => if v5 { 0x87 } else { 5 },
+        }
+    }
+}
+
+impl ConnAck {
+    //whether the session is present if the connection was accepted, why not if it wasn't
+    pub fn new(result: Result<bool, ConnectError>, version: ProtocolVersion) -> Self {
+        let (session_present, return_code) = match result {
+            Ok(session_present) => (session_present, SUCCESS),
+            Err(error) => (false, error.return_code(version)),
+        };
+        ConnAck { session_present: session_present, return_code: return_code, properties: vec![] }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Publish {
+    pub dup: bool,
+    pub qos: u8,
+    pub retain: bool,
+    pub topic: String,
+    pub packet_id: Option<u16>, //only there if QoS > 0
+    pub payload: Vec<u8>,
+    pub properties: Vec<Property>,
+}
+
+impl Publish {
+    //QoS 0 and not retained, anything else can be filled in with struct update syntax in with struct update syntax
+    pub fn new(topic: &str, payload: &[u8]) -> Self {
+        Publish {
+            dup: false,
+            qos: 0,
+            retain: false,
+            topic: topic.to_string(),
+            packet_id: None,
+            payload: payload.to_vec(),
+            properties: vec![],
+        }
+    }
+}
+
+//the reason code and properties of the publish acknowledgements only exist in MQTT 5
+#[derive(PartialEq, Debug, Clone)]
+pub struct PubAck {
+    pub packet_id: u16,
+    pub reason_code: u8,
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PubRec {
+    pub packet_id: u16,
+    pub reason_code: u8,
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PubRel {
+    pub packet_id: u16,
+    pub reason_code: u8,
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PubComp {
+    pub packet_id: u16,
+    pub reason_code: u8,
+    pub properties: Vec<Property>,
+}
+
+impl PubAck {
+    pub fn new(packet_id: u16) -> Self {
+        PubAck { packet_id: packet_id, reason_code: SUCCESS, properties: vec![] }
+    }
+}
+
+impl PubRec {
+    pub fn new(packet_id: u16) -> Self {
+        PubRec { packet_id: packet_id, reason_code: SUCCESS, properties: vec![] }
+    }
+}
+
+impl PubRel {
+    pub fn new(packet_id: u16) -> Self {
+        PubRel { packet_id: packet_id, reason_code: SUCCESS, properties: vec![] }
+    }
+}
+
+impl PubComp {
+    pub fn new(packet_id: u16) -> Self {
+        PubComp { packet_id: packet_id, reason_code: SUCCESS, properties: vec![] }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Subscribe {
+    pub packet_id: u16,
+    pub topics: Vec<SubscribeTopic>,
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct SubscribeTopic {
+    pub topic: String,
+    pub qos: u8,
+    //MQTT 5 subscription options
+    pub no_local: bool, //don't send the client what it publishes itself
+    pub retain_as_published: bool, //keep the RETAIN flag on forwarded messages
+    pub retain_handling: u8, //0 to always send retained messages on subscribe, 1 only if new, 2 never
+}
+
+impl SubscribeTopic {
+    pub fn new(topic: &str, qos: u8) -> Self {
+        SubscribeTopic {
+            topic: topic.to_string(),
+            qos: qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: 0,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct SubAck {
+    pub packet_id: u16,
+    pub return_codes: Vec<u8>, //reason codes for MQTT 5
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Unsubscribe {
+    pub packet_id: u16,
+    pub topics: Vec<String>,
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct UnsubAck {
+    pub packet_id: u16,
+    pub reason_codes: Vec<u8>, //one per topic, MQTT 5 only
+    pub properties: Vec<Property>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PingReq;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PingResp;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Disconnect {
+    pub reason_code: u8, //MQTT 5 only
+    pub properties: Vec<Property>,
+}
+
+impl Disconnect {
+    pub fn new() -> Self {
+        Disconnect { reason_code: SUCCESS, properties: vec![] }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Auth {
+    pub reason_code: u8,
+    pub properties: Vec<Property>,
+}
+
+//MQTT 5 reason codes, the ones below 0x80 mean things went well
+pub const SUCCESS: u8 = 0x00;
+pub const DISCONNECT_WITH_WILL_MESSAGE: u8 = 0x04;
+pub const NO_SUBSCRIPTION_EXISTED: u8 = 0x11;
+pub const CONTINUE_AUTHENTICATION: u8 = 0x18;
+pub const RE_AUTHENTICATE: u8 = 0x19;
+pub const UNSPECIFIED_ERROR: u8 = 0x80;
+pub const MALFORMED_PACKET: u8 = 0x81;
+pub const PROTOCOL_ERROR: u8 = 0x82;
+pub const TOPIC_FILTER_INVALID: u8 = 0x8f;
+pub const KEEP_ALIVE_TIMEOUT: u8 = 0x8d;
+pub const SESSION_TAKEN_OVER: u8 = 0x8e;
+pub const PACKET_IDENTIFIER_NOT_FOUND: u8 = 0x92;
+
+//the return code in SUBACK for a filter that wasn't subscribed to
+pub const SUBACK_FAILURE: u8 = 0x80;
+
+//topics are length-prefixed strings, they can't be any longer
+pub const MAX_TOPIC_LENGTH: usize = 65_535;
+
+fn is_wildcard(c: char) -> bool {
+    c == '+' || c == '#'
+}
+
+fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && topic.len() <= MAX_TOPIC_LENGTH && !topic.contains('\u{0}')
+}
+
+//what gets published to, wildcards aren't allowed
+pub fn valid_topic_name(topic: &str) -> bool {
+    valid_topic(topic) && !topic.contains(is_wildcard)
+}
+
+//what gets subscribed to: wildcards take up a whole level and '#' can only be the last one
+pub fn valid_topic_filter(topic: &str) -> bool {
+    if !valid_topic(topic) {
+        return false;
+    }
+
+    let levels: Vec<&str> = topic.split('/').collect();
+    levels.iter().enumerate().all(|(i, level)| {
+        match *level {
+            "+" => true,
+            "#" => i == levels.len() - 1,
+            _ => !level.contains(is_wildcard),
+        }
+    })
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Vec<u8>),
+    SubscriptionIdentifier(usize),
+    SessionExpiryInterval(u32),
+    AssignedClientIdentifier(String),
+    ServerKeepAlive(u16),
+    AuthenticationMethod(String),
+    AuthenticationData(Vec<u8>),
+    RequestProblemInformation(u8),
+    WillDelayInterval(u32),
+    RequestResponseInformation(u8),
+    ResponseInformation(String),
+    ServerReference(String),
+    ReasonString(String),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQoS(u8),
+    RetainAvailable(u8),
+    UserProperty(String, String),
+    MaximumPacketSize(u32),
+    WildcardSubscriptionAvailable(u8),
+    SubscriptionIdentifierAvailable(u8),
+    SharedSubscriptionAvailable(u8),
+}
+
+//where a list of properties is found: either a packet or the will in CONNECT
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum PropertyContext {
+    Packet(MqttType),
+    Will,
+}
+
+impl Property {
+    pub fn id(&self) -> u8 {
+        match *self {
+            Property::PayloadFormatIndicator(_) => 0x01,
+            Property::MessageExpiryInterval(_) => 0x02,
+            Property::ContentType(_) => 0x03,
+            Property::ResponseTopic(_) => 0x08,
+            Property::CorrelationData(_) => 0x09,
+            Property::SubscriptionIdentifier(_) => 0x0b,
+            Property::SessionExpiryInterval(_) => 0x11,
+            Property::AssignedClientIdentifier(_) => 0x12,
+            Property::ServerKeepAlive(_) => 0x13,
+            Property::AuthenticationMethod(_) => 0x15,
+            Property::AuthenticationData(_) => 0x16,
+            Property::RequestProblemInformation(_) => 0x17,
+            Property::WillDelayInterval(_) => 0x18,
+            Property::RequestResponseInformation(_) => 0x19,
+            Property::ResponseInformation(_) => 0x1a,
+            Property::ServerReference(_) => 0x1c,
+            Property::ReasonString(_) => 0x1f,
+            Property::ReceiveMaximum(_) => 0x21,
+            Property::TopicAliasMaximum(_) => 0x22,
+            Property::TopicAlias(_) => 0x23,
+            Property::MaximumQoS(_) => 0x24,
+            Property::RetainAvailable(_) => 0x25,
+            Property::UserProperty(_, _) => 0x26,
+            Property::MaximumPacketSize(_) => 0x27,
+            Property::WildcardSubscriptionAvailable(_) => 0x28,
+            Property::SubscriptionIdentifierAvailable(_) => 0x29,
+            Property::SharedSubscriptionAvailable(_) => 0x2a,
+        }
+    }
+
+    //straight from the table in section 2.2.2.2 of the MQTT 5 spec
+    fn allowed_in(&self, context: PropertyContext) -> bool {
+        use self::MqttType::*;
+        let packet_type = match context {
+            PropertyContext::Will => {
+                return match *self {
+                    Property::PayloadFormatIndicator(_) | Property::MessageExpiryInterval(_) |
+                    Property::ContentType(_) | Property::ResponseTopic(_) |
+                    Property::CorrelationData(_) | Property::WillDelayInterval(_) |
+                    Property::UserProperty(_, _) => true,
+                    _ => false,
+                }
+            }
+            PropertyContext::Packet(packet_type) => packet_type,
+        };
+
+        match *self {
+            Property::PayloadFormatIndicator(_) | Property::MessageExpiryInterval(_) |
+            Property::ContentType(_) | Property::ResponseTopic(_) |
+            Property::CorrelationData(_) | Property::TopicAlias(_) => packet_type == Publish,
+            Property::SubscriptionIdentifier(_) => packet_type == Publish || packet_type == Subscribe,
+            Property::SessionExpiryInterval(_) =>
+                packet_type == Connect || packet_type == ConnAck || packet_type == Disconnect,
+            Property::AuthenticationMethod(_) | Property::AuthenticationData(_) =>
+                packet_type == Connect || packet_type == ConnAck || packet_type == Auth,
+            Property::RequestProblemInformation(_) | Property::RequestResponseInformation(_) =>
+                packet_type == Connect,
+            Property::WillDelayInterval(_) => false,
+            Property::ReceiveMaximum(_) | Property::TopicAliasMaximum(_) |
+            Property::MaximumPacketSize(_) => packet_type == Connect || packet_type == ConnAck,
+            Property::AssignedClientIdentifier(_) | Property::ServerKeepAlive(_) |
+            Property::ResponseInformation(_) | Property::MaximumQoS(_) |
+            Property::RetainAvailable(_) | Property::WildcardSubscriptionAvailable(_) |
+            Property::SubscriptionIdentifierAvailable(_) |
+            Property::SharedSubscriptionAvailable(_) => packet_type == ConnAck,
+            Property::ServerReference(_) => packet_type == ConnAck || packet_type == Disconnect,
+            Property::ReasonString(_) => match packet_type {
+                ConnAck | PubAck | PubRec | PubRel | PubComp | SubAck | UnsubAck | Disconnect | Auth => true,
+                _ => false,
+            },
+            Property::UserProperty(_, _) => match packet_type {
+                PingReq | PingResp => false,
+                _ => true,
+            },
+        }
+    }
+
+    //the only ones that may show up more than once
+    fn may_repeat(&self) -> bool {
+        match *self {
+            Property::UserProperty(_, _) | Property::SubscriptionIdentifier(_) => true,
+            _ => false,
+        }
+    }
+
+    fn has_valid_value(&self) -> bool {
+        match *self {
+            Property::PayloadFormatIndicator(value) | Property::RequestProblemInformation(value) |
+            Property::RequestResponseInformation(value) | Property::MaximumQoS(value) |
+            Property::RetainAvailable(value) | Property::WildcardSubscriptionAvailable(value) |
+            Property::SubscriptionIdentifierAvailable(value) |
+            Property::SharedSubscriptionAvailable(value) => value <= 1,
+            Property::ReceiveMaximum(value) | Property::TopicAlias(value) => value != 0,
+            Property::MaximumPacketSize(value) => value != 0,
+            Property::SubscriptionIdentifier(value) => value != 0,
+            _ => true,
+        }
+    }
+}
+
+//U+FDD0 to U+FDEF and the last two code points of every plane
+fn is_non_character(c: char) -> bool {
+    let c = c as u32;
+    (c >= 0xfdd0 && c <= 0xfdef) || (c & 0xfffe) == 0xfffe
+}
+
+//what MQTT calls a UTF-8 encoded string: UTF-16 surrogates are already invalid UTF-8
+//as far as Rust is concerned, but U+0000 isn't allowed either
+pub fn mqtt_string(bytes: &[u8], non_characters: NonCharacters) -> Result<String, DecodeError> {
+    let string = String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)?;
+    let disallowed = string.chars().find(|&c| {
+        c == '\u{0}' || (non_characters == NonCharacters::Reject && is_non_character(c))
+    });
+
+    match disallowed {
+        Some(c) => Err(DecodeError::DisallowedCharacter(c)),
+        None => Ok(string),
+    }
+}
+
+//reads big-endian integers and length-prefixed fields off the front of a slice
+struct Reader<'a> {
+    bytes: &'a [u8],
+    non_characters: NonCharacters,
+}
+
+impl<'a> Reader<'a> {
+    fn with_non_characters(bytes: &'a [u8], non_characters: NonCharacters) -> Self {
+        Reader { bytes: bytes, non_characters: non_characters }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        self.take(2).map(|b| ((b[0] as u16) << 8) + b[1] as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.take(4).map(|b| ((b[0] as u32) << 24) + ((b[1] as u32) << 16) +
+                         ((b[2] as u32) << 8) + b[3] as u32)
+    }
+
+    //0 is not a legal packet identifier
+    fn packet_id(&mut self) -> Result<u16, DecodeError> {
+        match self.u16()? {
+            0 => Err(DecodeError::InvalidPacketId),
+            packet_id => Ok(packet_id),
+        }
+    }
+
+    fn binary(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let non_characters = self.non_characters;
+        mqtt_string(self.binary()?, non_characters)
+    }
+
+    fn variable_byte_integer(&mut self) -> Result<usize, DecodeError> {
+        let (value, len) = variable_byte_integer(self.bytes)?;
+        self.take(len)?;
+        Ok(value)
+    }
+
+    fn property(&mut self) -> Result<Property, DecodeError> {
+        let id = self.u8()?;
+        let property = match id {
+            0x01 => Property::PayloadFormatIndicator(self.u8()?),
+            0x02 => Property::MessageExpiryInterval(self.u32()?),
+            0x03 => Property::ContentType(self.string()?),
+            0x08 => Property::ResponseTopic(self.string()?),
+            0x09 => Property::CorrelationData(self.binary()?.to_vec()),
+            0x0b => Property::SubscriptionIdentifier(self.variable_byte_integer()?),
+            0x11 => Property::SessionExpiryInterval(self.u32()?),
+            0x12 => Property::AssignedClientIdentifier(self.string()?),
+            0x13 => Property::ServerKeepAlive(self.u16()?),
+            0x15 => Property::AuthenticationMethod(self.string()?),
+            0x16 => Property::AuthenticationData(self.binary()?.to_vec()),
+            0x17 => Property::RequestProblemInformation(self.u8()?),
+            0x18 => Property::WillDelayInterval(self.u32()?),
+            0x19 => Property::RequestResponseInformation(self.u8()?),
+            0x1a => Property::ResponseInformation(self.string()?),
+            0x1c => Property::ServerReference(self.string()?),
+            0x1f => Property::ReasonString(self.string()?),
+            0x21 => Property::ReceiveMaximum(self.u16()?),
+            0x22 => Property::TopicAliasMaximum(self.u16()?),
+            0x23 => Property::TopicAlias(self.u16()?),
+            0x24 => Property::MaximumQoS(self.u8()?),
+            0x25 => Property::RetainAvailable(self.u8()?),
+            0x26 => Property::UserProperty(self.string()?, self.string()?),
+            0x27 => Property::MaximumPacketSize(self.u32()?),
+            0x28 => Property::WildcardSubscriptionAvailable(self.u8()?),
+            0x29 => Property::SubscriptionIdentifierAvailable(self.u8()?),
+            0x2a => Property::SharedSubscriptionAvailable(self.u8()?),
+            _ => return Err(DecodeError::InvalidProperty(id)),
+        };
+
+        if !property.has_valid_value() {
+            return Err(DecodeError::InvalidProperty(id));
+        }
+        Ok(property)
+    }
+
+    fn properties(&mut self, context: PropertyContext) -> Result<Vec<Property>, DecodeError> {
+        let len = self.variable_byte_integer()?;
+        let mut reader = Reader::with_non_characters(self.take(len)?, self.non_characters);
+        let mut properties: Vec<Property> = vec![];
+        while !reader.is_empty() {
+            let property = reader.property()?;
+            if !property.allowed_in(context) {
+                return Err(DecodeError::InvalidProperty(property.id()));
+            }
+            if !property.may_repeat() && properties.iter().any(|p| p.id() == property.id()) {
+                return Err(DecodeError::DuplicateProperty(property.id()));
+            }
+            properties.push(property);
+        }
+        Ok(properties)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = self.bytes;
+        self.bytes = &[];
+        rest
+    }
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push((value >> 8) as u8);
+    bytes.push(value as u8);
+}
+
+fn push_binary(bytes: &mut Vec<u8>, binary: &[u8]) {
+    push_u16(bytes, binary.len() as u16);
+    bytes.extend_from_slice(binary);
+}
+
+fn push_string(bytes: &mut Vec<u8>, string: &str) {
+    push_binary(bytes, string.as_bytes());
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    push_u16(bytes, (value >> 16) as u16);
+    push_u16(bytes, value as u16);
+}
+
+fn push_property(bytes: &mut Vec<u8>, property: &Property) {
+    bytes.push(property.id());
+    match *property {
+        Property::PayloadFormatIndicator(value) | Property::RequestProblemInformation(value) |
+        Property::RequestResponseInformation(value) | Property::MaximumQoS(value) |
+        Property::RetainAvailable(value) | Property::WildcardSubscriptionAvailable(value) |
+        Property::SubscriptionIdentifierAvailable(value) |
+        Property::SharedSubscriptionAvailable(value) => bytes.push(value),
+        Property::ServerKeepAlive(value) | Property::ReceiveMaximum(value) |
+        Property::TopicAliasMaximum(value) | Property::TopicAlias(value) => push_u16(bytes, value),
+        Property::MessageExpiryInterval(value) | Property::SessionExpiryInterval(value) |
+        Property::WillDelayInterval(value) | Property::MaximumPacketSize(value) => push_u32(bytes, value),
+        Property::ContentType(ref value) | Property::ResponseTopic(ref value) |
+        Property::AssignedClientIdentifier(ref value) | Property::AuthenticationMethod(ref value) |
+        Property::ResponseInformation(ref value) | Property::ServerReference(ref value) |
+        Property::ReasonString(ref value) => push_string(bytes, value),
+        Property::CorrelationData(ref value) | Property::AuthenticationData(ref value) =>
+            push_binary(bytes, value),
+        Property::SubscriptionIdentifier(value) => encode_remaining_length(bytes, value),
+        Property::UserProperty(ref key, ref value) => {
+            push_string(bytes, key);
+            push_string(bytes, value);
+        }
+    }
+}
+
+fn push_properties(bytes: &mut Vec<u8>, properties: &[Property]) {
+    let mut encoded = vec![];
+    for property in properties {
+        push_property(&mut encoded, property);
+    }
+    encode_remaining_length(bytes, encoded.len());
+    bytes.extend_from_slice(&encoded);
+}
+
+//the variable header and payload of a packet, without the fixed header
+fn packet_body(bytes: &[u8]) -> Result<&[u8], DecodeError> {
+    let total_len = total_length(bytes)?;
+    if total_len > bytes.len() {
+        return Err(DecodeError::Truncated);
+    }
+
+    Ok(&bytes[fixed_header_length(bytes)? .. total_len])
+}
+
+const PUBLISH_DUP: u8 = 0x08;
+const PUBLISH_QOS: u8 = 0x06;
+const PUBLISH_RETAIN: u8 = 0x01;
+
+fn check_flags(packet_type: MqttType, flags: u8, version: ProtocolVersion) -> Result<(), DecodeError> {
+    let reserved_ok = match packet_type {
+        MqttType::Publish => true,
+        //3.1 clients send DUP and QoS on these as they please, so don't hold it
+        //against them. From 3.1.1 on they must be 0b0010.
+        MqttType::PubRel | MqttType::Subscribe | MqttType::Unsubscribe =>
+            version == ProtocolVersion::V31 || flags == 0x02,
+        _ => flags == 0,
+    };
+
+    if reserved_ok { Ok(()) } else { Err(DecodeError::ReservedFlagsSet) }
+}
+
+//decodes one complete packet, fixed header included, as sent by a 3.1 or 3.1.1 client
+pub fn decode(bytes: &[u8]) -> Result<Packet, DecodeError> {
+    decode_version(bytes, ProtocolVersion::V311)
+}
+
+//CONNECT says which version it is, everything after it is decoded
+//according to the version negotiated with that connection
+pub fn decode_version(bytes: &[u8], version: ProtocolVersion) -> Result<Packet, DecodeError> {
+    decode_with(bytes, version, NonCharacters::Allow)
+}
+
+pub fn decode_with(bytes: &[u8], version: ProtocolVersion, non_characters: NonCharacters)
+                   -> Result<Packet, DecodeError> {
+    let packet_type = message_type(bytes)?;
+    let flags = bytes[0] & 0x0f;
+    check_flags(packet_type, flags, version)?;
+
+    let v5 = version == ProtocolVersion::V5;
+    if packet_type == MqttType::Auth && !v5 {
+        return Err(DecodeError::UnknownPacketType(MqttType::Auth as u8));
+    }
+
+    let context = PropertyContext::Packet(packet_type);
+    let mut reader = Reader::with_non_characters(packet_body(bytes)?, non_characters);
+    let packet = match packet_type {
+        MqttType::Connect => Packet::Connect(read_connect(&mut reader)?),
+        MqttType::ConnAck => {
+            let session_present = (reader.u8()? & 0x01) != 0;
+            let return_code = reader.u8()?;
+            let properties = if v5 { reader.properties(context)? } else { vec![] };
+            Packet::ConnAck(ConnAck {
+                session_present: session_present,
+                return_code: return_code,
+                properties: properties,
+            })
+        }
+        MqttType::Publish => {
+            let qos = (flags & PUBLISH_QOS) >> 1;
+            if qos > 2 {
+                return Err(DecodeError::InvalidQoS);
+            }
+            let topic = reader.string()?;
+            if !valid_topic_name(&topic) {
+                return Err(DecodeError::InvalidTopic);
+            }
+            let packet_id = if qos > 0 { Some(reader.packet_id()?) } else { None };
+            let properties = if v5 { reader.properties(context)? } else { vec![] };
+            Packet::Publish(Publish {
+                dup: (flags & PUBLISH_DUP) != 0,
+                qos: qos,
+                retain: (flags & PUBLISH_RETAIN) != 0,
+                topic: topic,
+                packet_id: packet_id,
+                payload: reader.rest().to_vec(),
+                properties: properties,
+            })
+        }
+        MqttType::PubAck => {
+            let (packet_id, reason_code, properties) = read_publish_ack(&mut reader, context, v5)?;
+            Packet::PubAck(PubAck { packet_id: packet_id, reason_code: reason_code, properties: properties })
+        }
+        MqttType::PubRec => {
+            let (packet_id, reason_code, properties) = read_publish_ack(&mut reader, context, v5)?;
+            Packet::PubRec(PubRec { packet_id: packet_id, reason_code: reason_code, properties: properties })
+        }
+        MqttType::PubRel => {
+            let (packet_id, reason_code, properties) = read_publish_ack(&mut reader, context, v5)?;
+            Packet::PubRel(PubRel { packet_id: packet_id, reason_code: reason_code, properties: properties })
+        }
+        MqttType::PubComp => {
+            let (packet_id, reason_code, properties) = read_publish_ack(&mut reader, context, v5)?;
+            Packet::PubComp(PubComp { packet_id: packet_id, reason_code: reason_code, properties: properties })
+        }
+        MqttType::Subscribe => {
+            let packet_id = reader.packet_id()?;
+            let properties = if v5 { reader.properties(context)? } else { vec![] };
+            let mut topics = vec![];
+            while !reader.is_empty() {
+                let topic = reader.string()?;
+                topics.push(read_subscription_options(&mut reader, &topic, v5)?);
+            }
+            if topics.is_empty() {
+                return Err(DecodeError::NoTopics);
+            }
+            Packet::Subscribe(Subscribe { packet_id: packet_id, topics: topics, properties: properties })
+        }
+        MqttType::SubAck => {
+            let packet_id = reader.packet_id()?;
+            let properties = if v5 { reader.properties(context)? } else { vec![] };
+            Packet::SubAck(SubAck {
+                packet_id: packet_id,
+                return_codes: reader.rest().to_vec(),
+                properties: properties,
+            })
+        }
+        MqttType::Unsubscribe => {
+            let packet_id = reader.packet_id()?;
+            let properties = if v5 { reader.properties(context)? } else { vec![] };
+            let mut topics = vec![];
+            while !reader.is_empty() {
+                topics.push(reader.string()?);
+            }
+            if topics.is_empty() {
+                return Err(DecodeError::NoTopics);
+            }
+            Packet::Unsubscribe(Unsubscribe { packet_id: packet_id, topics: topics, properties: properties })
+        }
+        MqttType::UnsubAck => {
+            let packet_id = reader.packet_id()?;
+            let properties = if v5 { reader.properties(context)? } else { vec![] };
+            Packet::UnsubAck(UnsubAck {
+                packet_id: packet_id,
+                reason_codes: reader.rest().to_vec(),
+                properties: properties,
+            })
+        }
+        MqttType::PingReq => Packet::PingReq(PingReq),
+        MqttType::PingResp => Packet::PingResp(PingResp),
+        MqttType::Disconnect => {
+            let (reason_code, properties) = read_reason(&mut reader, context, v5)?;
+            Packet::Disconnect(Disconnect { reason_code: reason_code, properties: properties })
+        }
+        MqttType::Auth => {
+            let (reason_code, properties) = read_reason(&mut reader, context, v5)?;
+            match reason_code {
+                SUCCESS | CONTINUE_AUTHENTICATION | RE_AUTHENTICATE => {}
+                other => return Err(DecodeError::InvalidReasonCode(other)),
+            }
+            Packet::Auth(Auth { reason_code: reason_code, properties: properties })
+        }
+    };
+
+    Ok(packet)
+}
+
+//the byte after each topic filter, where MQTT 5 packs more options in with the QoS
+fn read_subscription_options(reader: &mut Reader, topic: &str, v5: bool) -> Result<SubscribeTopic, DecodeError> {
+    let options = reader.u8()?;
+    let reserved = if v5 { SUBSCRIBE_RESERVED_V5 } else { SUBSCRIBE_RESERVED };
+    if (options & reserved) != 0 {
+        return Err(DecodeError::ReservedFlagsSet);
+    }
+
+    let qos = options & SUBSCRIBE_QOS;
+    let retain_handling = (options & SUBSCRIBE_RETAIN_HANDLING) >> 4;
+    if qos > 2 {
+        return Err(DecodeError::InvalidQoS);
+    }
+    if retain_handling > 2 {
+        return Err(DecodeError::InvalidSubscriptionOptions);
+    }
+
+    Ok(SubscribeTopic {
+        no_local: (options & SUBSCRIBE_NO_LOCAL) != 0,
+        retain_as_published: (options & SUBSCRIBE_RETAIN_AS_PUBLISHED) != 0,
+        retain_handling: retain_handling,
+        ..SubscribeTopic::new(topic, qos)
+    })
 }
 
-pub fn message_type(bytes: &[u8]) -> MqttType {
-    unsafe { mem::transmute((bytes[0] & 0xf0) >> 4) }
+fn subscription_options(topic: &SubscribeTopic, v5: bool) -> u8 {
+    let mut options = topic.qos;
+    if v5 {
+        if topic.no_local {
+            options |= SUBSCRIBE_NO_LOCAL;
+        }
+        if topic.retain_as_published {
+            options |= SUBSCRIBE_RETAIN_AS_PUBLISHED;
+        }
+        options |= topic.retain_handling << 4;
+    }
+    options
+}
+
+//MQTT 5 leaves out the reason code and properties when it's a plain success
+fn read_reason(reader: &mut Reader, context: PropertyContext, v5: bool)
+               -> Result<(u8, Vec<Property>), DecodeError> {
+    let reason_code = if v5 && !reader.is_empty() { reader.u8()? } else { SUCCESS };
+    let properties = if v5 && !reader.is_empty() { reader.properties(context)? } else { vec![] };
+    Ok((reason_code, properties))
+}
+
+fn read_publish_ack(reader: &mut Reader, context: PropertyContext, v5: bool)
+                    -> Result<(u16, u8, Vec<Property>), DecodeError> {
+    let packet_id = reader.packet_id()?;
+    let (reason_code, properties) = read_reason(reader, context, v5)?;
+    Ok((packet_id, reason_code, properties))
 }
 
+const SUBSCRIBE_QOS: u8 = 0x03;
+const SUBSCRIBE_NO_LOCAL: u8 = 0x04;
+const SUBSCRIBE_RETAIN_AS_PUBLISHED: u8 = 0x08;
+const SUBSCRIBE_RETAIN_HANDLING: u8 = 0x30;
+const SUBSCRIBE_RESERVED: u8 = 0xfc;
+const SUBSCRIBE_RESERVED_V5: u8 = 0xc0;
+
+const CONNECT_USERNAME: u8 = 0x80;
+const CONNECT_PASSWORD: u8 = 0x40;
+const CONNECT_WILL_RETAIN: u8 = 0x20;
+const CONNECT_WILL_QOS: u8 = 0x18;
+const CONNECT_WILL: u8 = 0x04;
+const CONNECT_CLEAN_SESSION: u8 = 0x02;
+const CONNECT_RESERVED: u8 = 0x01;
+
+fn read_connect(reader: &mut Reader) -> Result<Connect, DecodeError> {
+    let protocol_name = reader.string()?;
+    let protocol_level = reader.u8()?;
+    let version = ProtocolVersion::new(&protocol_name, protocol_level)
+        .ok_or(DecodeError::UnacceptableProtocolVersion)?;
+    let v5 = version == ProtocolVersion::V5;
+    let flags = reader.u8()?;
+    let keep_alive = reader.u16()?;
+    let properties = if v5 {
+        reader.properties(PropertyContext::Packet(MqttType::Connect))?
+    } else {
+        vec![]
+    };
+
+    if (flags & CONNECT_RESERVED) != 0 {
+        return Err(DecodeError::ReservedFlagsSet);
+    }
+
+    let will_qos = (flags & CONNECT_WILL_QOS) >> 3;
+    let has_will = (flags & CONNECT_WILL) != 0;
+    if will_qos > 2 || (!has_will && (flags & (CONNECT_WILL_QOS | CONNECT_WILL_RETAIN)) != 0) {
+        return Err(DecodeError::InvalidConnectFlags);
+    }
+
+    //3.1.1 has no passwords without usernames, MQTT 5 lets them be used for other things
+    if version == ProtocolVersion::V311 && (flags & CONNECT_PASSWORD) != 0 && (flags & CONNECT_USERNAME) == 0 {
+        return Err(DecodeError::InvalidConnectFlags);
+    }
+
+    //the payload fields are in a fixed order and only there if their flag is set
+    let client_id = reader.string()?;
+    let will = if has_will {
+        let will_properties = if v5 { reader.properties(PropertyContext::Will)? } else { vec![] };
+        let will_topic = reader.string()?;
+        if !valid_topic_name(&will_topic) {
+            return Err(DecodeError::InvalidTopic);
+        }
+        Some(Will {
+            topic: will_topic,
+            message: reader.binary()?.to_vec(),
+            qos: will_qos,
+            retain: (flags & CONNECT_WILL_RETAIN) != 0,
+            properties: will_properties,
+        })
+    } else {
+        None
+    };
+    let username = if (flags & CONNECT_USERNAME) != 0 { Some(reader.string()?) } else { None };
+    let password = if (flags & CONNECT_PASSWORD) != 0 { Some(reader.binary()?.to_vec()) } else { None };
+
+    Ok(Connect {
+        version: version,
+        clean_session: (flags & CONNECT_CLEAN_SESSION) != 0,
+        keep_alive: keep_alive,
+        client_id: client_id,
+        will: will,
+        username: username,
+        password: password,
+        properties: properties,
+    })
+}
+
+fn write_connect(body: &mut Vec<u8>, connect: &Connect) {
+    let mut flags = 0;
+    if connect.username.is_some() {
+        flags |= CONNECT_USERNAME;
+    }
+    if connect.password.is_some() {
+        flags |= CONNECT_PASSWORD;
+    }
+    if let Some(ref will) = connect.will {
+        flags |= CONNECT_WILL | (will.qos << 3);
+        if will.retain {
+            flags |= CONNECT_WILL_RETAIN;
+        }
+    }
+    if connect.clean_session {
+        flags |= CONNECT_CLEAN_SESSION;
+    }
+
+    let v5 = connect.version == ProtocolVersion::V5;
+    push_string(body, connect.version.name());
+    body.push(connect.version.level());
+    body.push(flags);
+    push_u16(body, connect.keep_alive);
+    if v5 {
+        push_properties(body, &connect.properties);
+    }
+    push_string(body, &connect.client_id);
+    if let Some(ref will) = connect.will {
+        if v5 {
+            push_properties(body, &will.properties);
+        }
+        push_string(body, &will.topic);
+        push_binary(body, &will.message);
+    }
+    if let Some(ref username) = connect.username {
+        push_string(body, username);
+    }
+    if let Some(ref password) = connect.password {
+        push_binary(body, password);
+    }
+}
+
+fn push_reason(bytes: &mut Vec<u8>, reason_code: u8, properties: &[Property]) {
+    if reason_code != SUCCESS || !properties.is_empty() {
+        bytes.push(reason_code);
+    }
+    if !properties.is_empty() {
+        push_properties(bytes, properties);
+    }
+}
+
+impl Packet {
+    //encodes for 3.1.1 clients
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        self.encode_version(ProtocolVersion::V311, bytes)
+    }
+
+    //properties and reason codes are left out unless it's for MQTT 5
+    pub fn encode_version(&self, version: ProtocolVersion, bytes: &mut Vec<u8>) {
+        let v5 = version == ProtocolVersion::V5;
+        let mut body = vec![];
+        let first_byte = match *self {
+            Packet::Connect(ref connect) => {
+                write_connect(&mut body, connect);
+                0x10
+            }
+            Packet::ConnAck(ref connack) => {
+                //3.1 has no session present flag, the whole byte is reserved
+                let session_present = connack.session_present && version != ProtocolVersion::V31;
+                body.push(if session_present { 1 } else { 0 });
+                body.push(connack.return_code);
+                if v5 {
+                    push_properties(&mut body, &connack.properties);
+                }
+                0x20
+            }
+            Packet::Publish(ref publish) => {
+                push_string(&mut body, &publish.topic);
+                if let Some(packet_id) = publish.packet_id {
+                    push_u16(&mut body, packet_id);
+                }
+                if v5 {
+                    push_properties(&mut body, &publish.properties);
+                }
+                body.extend_from_slice(&publish.payload);
+                let mut first_byte = 0x30 | (publish.qos << 1);
+                if publish.dup {
+                    first_byte |= PUBLISH_DUP;
+                }
+                if publish.retain {
+                    first_byte |= PUBLISH_RETAIN;
+                }
+                first_byte
+            }
+            Packet::PubAck(ref puback) => {
+                push_u16(&mut body, puback.packet_id);
+                if v5 {
+                    push_reason(&mut body, puback.reason_code, &puback.properties);
+                }
+                0x40
+            }
+            Packet::PubRec(ref pubrec) => {
+                push_u16(&mut body, pubrec.packet_id);
+                if v5 {
+                    push_reason(&mut body, pubrec.reason_code, &pubrec.properties);
+                }
+                0x50
+            }
+            Packet::PubRel(ref pubrel) => {
+                push_u16(&mut body, pubrel.packet_id);
+                if v5 {
+                    push_reason(&mut body, pubrel.reason_code, &pubrel.properties);
+                }
+                0x62
+            }
+            Packet::PubComp(ref pubcomp) => {
+                push_u16(&mut body, pubcomp.packet_id);
+                if v5 {
+                    push_reason(&mut body, pubcomp.reason_code, &pubcomp.properties);
+                }
+                0x70
+            }
+            Packet::Subscribe(ref subscribe) => {
+                push_u16(&mut body, subscribe.packet_id);
+                if v5 {
+                    push_properties(&mut body, &subscribe.properties);
+                }
+                for topic in &subscribe.topics {
+                    push_string(&mut body, &topic.topic);
+                    body.push(subscription_options(topic, v5));
+                }
+                0x82
+            }
+            Packet::SubAck(ref suback) => {
+                push_u16(&mut body, suback.packet_id);
+                if v5 {
+                    push_properties(&mut body, &suback.properties);
+                }
+                body.extend_from_slice(&suback.return_codes);
+                0x90
+            }
+            Packet::Unsubscribe(ref unsubscribe) => {
+                push_u16(&mut body, unsubscribe.packet_id);
+                if v5 {
+                    push_properties(&mut body, &unsubscribe.properties);
+                }
+                for topic in &unsubscribe.topics {
+                    push_string(&mut body, topic);
+                }
+                0xa2
+            }
+            Packet::UnsubAck(ref unsuback) => {
+                push_u16(&mut body, unsuback.packet_id);
+                if v5 {
+                    push_properties(&mut body, &unsuback.properties);
+                    body.extend_from_slice(&unsuback.reason_codes);
+                }
+                0xb0
+            }
+            Packet::PingReq(_) => 0xc0,
+            Packet::PingResp(_) => 0xd0,
+            Packet::Disconnect(ref disconnect) => {
+                if v5 {
+                    push_reason(&mut body, disconnect.reason_code, &disconnect.properties);
+                }
+                0xe0
+            }
+            Packet::Auth(ref auth) => {
+                push_reason(&mut body, auth.reason_code, &auth.properties);
+                0xf0
+            }
+        };
+
+        bytes.push(first_byte);
+        encode_remaining_length(bytes, body.len());
+        bytes.extend_from_slice(&body);
+    }
+}
+
+#[cfg(test)]
+fn round_trip(packet: Packet) {
+    let mut bytes = vec![];
+    packet.encode(&mut bytes);
+    assert_eq!(decode(&bytes), Ok(packet));
+}
+
+#[test]
+fn decode_connect() {
+    let connect = match decode(&connect_bytes()) {
+        Ok(Packet::Connect(connect)) => connect,
+        other => panic!("Expected a connect packet, got {:?}", other),
+    };
+    assert_eq!(connect.version, ProtocolVersion::V31);
+    assert_eq!(connect.clean_session, false);
+    assert_eq!(connect.keep_alive, 10);
+    assert_eq!(connect.client_id, "cid");
+    assert_eq!(connect.will, Some(Will { topic: "will".to_string(), message: b"wmsg".to_vec(),
+                                         qos: 1, retain: false, properties: vec![] }));
+    assert_eq!(connect.username, Some("gliftel".to_string()));
+    assert_eq!(connect.password, Some(b"pw".to_vec()));
+}
+
+#[test]
+fn decode_minimal_connect() {
+    let connect_bytes = vec![
+        0x10u8, 0x0f, // fixed header
+        0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+        0x04, // protocol version
+        0x02, // connection flags, clean session only
+        0x00, 0x3c, // keepalive
+        0x00, 0x03, 'c' as u8, 'i' as u8, 'd' as u8, // client ID
+        ];
+    assert_eq!(decode(&connect_bytes), Ok(Packet::Connect(Connect {
+        version: ProtocolVersion::V311,
+        clean_session: true,
+        keep_alive: 60,
+        client_id: "cid".to_string(),
+        will: None,
+        username: None,
+        password: None,
+        properties: vec![],
+    })));
+}
+
+#[test]
+fn decode_connect_v5() {
+    let connect_bytes = vec![
+        0x10u8, 0x15, // fixed header
+        0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+        0x05, // protocol version
+        0x06, // connection flags, clean start and will
+        0x00, 0x3c, // keepalive
+        0x00, // properties
+        0x00, 0x01, 'c' as u8, // client ID
+        0x00, // will properties
+        0x00, 0x01, 'w' as u8, // will topic
+        0x00, 0x01, 'm' as u8, // will msg
+        ];
+    let connect = Connect {
+        version: ProtocolVersion::V5,
+        clean_session: true,
+        keep_alive: 60,
+        client_id: "c".to_string(),
+        will: Some(Will { topic: "w".to_string(), message: b"m".to_vec(), qos: 0, retain: false,
+                          properties: vec![] }),
+        username: None,
+        password: None,
+        properties: vec![],
+    };
+    assert_eq!(decode(&connect_bytes), Ok(Packet::Connect(connect.clone())));
+
+    let mut bytes = vec![];
+    Packet::Connect(connect).encode(&mut bytes);
+    assert_eq!(bytes, connect_bytes);
+}
+
+#[test]
+fn decode_connect_bad_version() {
+    let mut connect_bytes = connect_bytes();
+    connect_bytes[10] = 4; //MQIsdp is 3.1 only
+    assert_eq!(decode(&connect_bytes), Err(DecodeError::UnacceptableProtocolVersion));
+    connect_bytes[10] = 6;
+    assert_eq!(decode(&connect_bytes), Err(DecodeError::UnacceptableProtocolVersion));
+    connect_bytes[4] = 'm' as u8;
+    connect_bytes[10] = 3;
+    assert_eq!(decode(&connect_bytes), Err(DecodeError::UnacceptableProtocolVersion));
+}
+
+#[test]
+fn protocol_versions() {
+    assert_eq!(ProtocolVersion::new("MQIsdp", 3), Some(ProtocolVersion::V31));
+    assert_eq!(ProtocolVersion::new("MQTT", 4), Some(ProtocolVersion::V311));
+    assert_eq!(ProtocolVersion::new("MQTT", 5), Some(ProtocolVersion::V5));
+    assert_eq!(ProtocolVersion::new("MQTT", 3), None);
+    for version in &[ProtocolVersion::V31, ProtocolVersion::V311, ProtocolVersion::V5] {
+        assert_eq!(ProtocolVersion::new(version.name(), version.level()), Some(*version));
+    }
+}
+
+#[test]
+fn encode_connack_versions() {
+    let connack = Packet::ConnAck(ConnAck { session_present: true, return_code: 0, properties: vec![] });
+    let mut bytes = vec![];
+    connack.encode_version(ProtocolVersion::V31, &mut bytes);
+    assert_eq!(bytes, vec![0x20, 2, 0, 0]);
+
+    let mut bytes = vec![];
+    connack.encode_version(ProtocolVersion::V311, &mut bytes);
+    assert_eq!(bytes, vec![0x20, 2, 1, 0]);
+
+    let mut bytes = vec![];
+    connack.encode_version(ProtocolVersion::V5, &mut bytes);
+    assert_eq!(bytes, vec![0x20, 3, 1, 0, 0]);
+}
+
+#[test]
+fn round_trip_v5() {
+    let packets = vec![
+        Packet::ConnAck(ConnAck {
+            session_present: false,
+            return_code: 0,
+            properties: vec![Property::AssignedClientIdentifier("auto-1".to_string()),
+                             Property::ServerKeepAlive(30),
+                             Property::MaximumQoS(1)],
+        }),
+        Packet::Publish(Publish {
+            dup: false,
+            qos: 1,
+            retain: false,
+            topic: "foo".to_string(),
+            packet_id: Some(3),
+            payload: vec![1, 2],
+            properties: vec![Property::ContentType("text/plain".to_string()),
+                             Property::CorrelationData(vec![9, 9]),
+                             Property::MessageExpiryInterval(3600),
+                             Property::UserProperty("a".to_string(), "1".to_string()),
+                             Property::UserProperty("a".to_string(), "2".to_string())],
+        }),
+        Packet::PubAck(PubAck::new(3)),
+        Packet::PubRec(PubRec { packet_id: 3, reason_code: 0x10, properties: vec![] }),
+        Packet::PubRel(PubRel {
+            packet_id: 3,
+            reason_code: 0x92,
+            properties: vec![Property::ReasonString("who?".to_string())],
+        }),
+        Packet::PubComp(PubComp::new(3)),
+        Packet::Subscribe(Subscribe {
+            packet_id: 4,
+            topics: vec![SubscribeTopic::new("foo/+", 1)],
+            properties: vec![Property::SubscriptionIdentifier(268435455)],
+        }),
+        Packet::SubAck(SubAck { packet_id: 4, return_codes: vec![1], properties: vec![] }),
+        Packet::Unsubscribe(Unsubscribe { packet_id: 5, topics: vec!["foo/+".to_string()],
+                                          properties: vec![] }),
+        Packet::UnsubAck(UnsubAck { packet_id: 5, reason_codes: vec![0, 0x11], properties: vec![] }),
+        Packet::Disconnect(Disconnect::new()),
+        Packet::Disconnect(Disconnect {
+            reason_code: 0x8e,
+            properties: vec![Property::SessionExpiryInterval(0)],
+        }),
+        Packet::Auth(Auth {
+            reason_code: CONTINUE_AUTHENTICATION,
+            properties: vec![Property::AuthenticationMethod("SCRAM-SHA-1".to_string()),
+                             Property::AuthenticationData(vec![1, 2, 3])],
+        }),
+        ];
+    for packet in packets {
+        let mut bytes = vec![];
+        packet.encode_version(ProtocolVersion::V5, &mut bytes);
+        assert_eq!(decode_version(&bytes, ProtocolVersion::V5), Ok(packet));
+    }
+}
+
+#[test]
+fn decode_publish_v5_properties() {
+    let pub_bytes = vec![
+        0x30, 0x0b, //fixed header
+        0x00, 0x03, 'f' as u8, 'o' as u8, 'o' as u8, //topic name
+        0x02, 0x01, 0x01, //properties, payload format indicator
+        'b' as u8, 'a' as u8, 'r' as u8, //payload
+        ];
+    assert_eq!(decode_version(&pub_bytes, ProtocolVersion::V5),
+               Ok(Packet::Publish(Publish { dup: false, qos: 0, retain: false, topic: "foo".to_string(),
+                                            packet_id: None, payload: b"bar".to_vec(),
+                                            properties: vec![Property::PayloadFormatIndicator(1)] })));
+}
+
+#[test]
+fn encode_v5_acks() {
+    let mut bytes = vec![];
+    Packet::PubAck(PubAck::new(0x1234)).encode_version(ProtocolVersion::V5, &mut bytes);
+    assert_eq!(bytes, vec![0x40, 2, 0x12, 0x34]);
+
+    let mut bytes = vec![];
+    Packet::PubAck(PubAck { packet_id: 1, reason_code: 0x10, properties: vec![] })
+        .encode_version(ProtocolVersion::V5, &mut bytes);
+    assert_eq!(bytes, vec![0x40, 3, 0, 1, 0x10]);
+
+    //reason codes are MQTT 5 only
+    let mut bytes = vec![];
+    Packet::PubAck(PubAck { packet_id: 1, reason_code: 0x10, properties: vec![] }).encode(&mut bytes);
+    assert_eq!(bytes, vec![0x40, 2, 0, 1]);
+
+    let mut bytes = vec![];
+    Packet::Disconnect(Disconnect::new()).encode_version(ProtocolVersion::V5, &mut bytes);
+    assert_eq!(bytes, vec![0xe0, 0]);
+
+    let mut bytes = vec![];
+    Packet::Disconnect(Disconnect { reason_code: 0x8e, properties: vec![] })
+        .encode_version(ProtocolVersion::V5, &mut bytes);
+    assert_eq!(bytes, vec![0xe0, 1, 0x8e]);
+}
+
+#[test]
+fn decode_v5_properties_not_allowed() {
+    //topic alias only makes sense on PUBLISH
+    let connack_bytes = vec![0x20, 6, 0, 0, 3, 0x23, 0, 1];
+    assert_eq!(decode_version(&connack_bytes, ProtocolVersion::V5),
+               Err(DecodeError::InvalidProperty(0x23)));
+
+    //and will delay interval only in the will
+    let pub_bytes = vec![0x30, 9, 0, 1, 't' as u8, 5, 0x18, 0, 0, 0, 1];
+    assert_eq!(decode_version(&pub_bytes, ProtocolVersion::V5),
+               Err(DecodeError::InvalidProperty(0x18)));
+
+    //unknown property
+    let pub_bytes = vec![0x30, 5, 0, 1, 't' as u8, 1, 0x7f];
+    assert_eq!(decode_version(&pub_bytes, ProtocolVersion::V5),
+               Err(DecodeError::InvalidProperty(0x7f)));
+}
+
+#[test]
+fn decode_v5_property_values() {
+    let pub_bytes = vec![0x30, 6, 0, 1, 't' as u8, 2, 0x01, 2]; //payload format indicator of 2
+    assert_eq!(decode_version(&pub_bytes, ProtocolVersion::V5),
+               Err(DecodeError::InvalidProperty(0x01)));
+
+    let pub_bytes = vec![0x30, 7, 0, 1, 't' as u8, 3, 0x23, 0, 0]; //topic alias 0
+    assert_eq!(decode_version(&pub_bytes, ProtocolVersion::V5),
+               Err(DecodeError::InvalidProperty(0x23)));
+}
+
+#[test]
+fn decode_v5_duplicate_properties() {
+    let pub_bytes = vec![0x30, 10, 0, 1, 't' as u8, 6, 0x23, 0, 1, 0x23, 0, 2];
+    assert_eq!(decode_version(&pub_bytes, ProtocolVersion::V5),
+               Err(DecodeError::DuplicateProperty(0x23)));
+
+    //subscription identifiers can repeat when sent to a client
+    let pub_bytes = vec![0x30, 8, 0, 1, 't' as u8, 4, 0x0b, 1, 0x0b, 2];
+    assert_eq!(decode_version(&pub_bytes, ProtocolVersion::V5),
+               Ok(Packet::Publish(Publish {
+                   dup: false,
+                   qos: 0,
+                   retain: false,
+                   topic: "t".to_string(),
+                   packet_id: None,
+                   payload: vec![],
+                   properties: vec![Property::SubscriptionIdentifier(1),
+                                    Property::SubscriptionIdentifier(2)],
+               })));
+}
+
+#[test]
+fn decode_v5_will_properties() {
+    let connect_bytes = vec![
+        0x10u8, 0x1a, // fixed header
+        0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+        0x05, // protocol version
+        0x06, // connection flags, clean start and will
+        0x00, 0x3c, // keepalive
+        0x00, // properties
+        0x00, 0x01, 'c' as u8, // client ID
+        0x05, 0x18, 0x00, 0x00, 0x00, 0x0a, // will properties, will delay interval
+        0x00, 0x01, 'w' as u8, // will topic
+        0x00, 0x01, 'm' as u8, // will msg
+        ];
+    let will = match decode(&connect_bytes) {
+        Ok(Packet::Connect(connect)) => connect.will.expect("No will in connect"),
+        other => panic!("Expected a connect packet, got {:?}", other),
+    };
+    assert_eq!(will.properties, vec![Property::WillDelayInterval(10)]);
+}
+
+#[test]
+fn decode_auth() {
+    assert_eq!(decode_version(&[0xf0, 0], ProtocolVersion::V5),
+               Ok(Packet::Auth(Auth { reason_code: SUCCESS, properties: vec![] })));
+    assert_eq!(decode(&[0xf0, 0]), Err(DecodeError::UnknownPacketType(0xf)));
+    assert_eq!(decode_version(&[0xf0, 2, RE_AUTHENTICATE, 0], ProtocolVersion::V5),
+               Ok(Packet::Auth(Auth { reason_code: RE_AUTHENTICATE, properties: vec![] })));
+    assert_eq!(decode_version(&[0xf0, 2, PROTOCOL_ERROR, 0], ProtocolVersion::V5),
+               Err(DecodeError::InvalidReasonCode(PROTOCOL_ERROR)));
+}
+
+#[test]
+fn decode_connect_bad_flags() {
+    let mut connect_bytes = connect_bytes();
+    connect_bytes[11] = 0xcd; //reserved bit
+    assert_eq!(decode(&connect_bytes), Err(DecodeError::ReservedFlagsSet));
+    connect_bytes[11] = 0xdc; //will QoS 3
+    assert_eq!(decode(&connect_bytes), Err(DecodeError::InvalidConnectFlags));
+    connect_bytes[11] = 0xe8; //will retain and QoS without a will
+    assert_eq!(decode(&connect_bytes), Err(DecodeError::InvalidConnectFlags));
+}
+
+#[test]
+fn decode_connect_password_without_username() {
+    let connect = Connect {
+        version: ProtocolVersion::V311,
+        clean_session: true,
+        keep_alive: 10,
+        client_id: "cid".to_string(),
+        will: None,
+        username: None,
+        password: Some(b"pw".to_vec()),
+        properties: vec![],
+    };
+    let mut bytes = vec![];
+    Packet::Connect(connect.clone()).encode(&mut bytes);
+    assert_eq!(decode(&bytes), Err(DecodeError::InvalidConnectFlags));
+
+    let connect = Packet::Connect(Connect { version: ProtocolVersion::V5, ..connect });
+    let mut bytes = vec![];
+    connect.encode_version(ProtocolVersion::V5, &mut bytes);
+    assert_eq!(decode_version(&bytes, ProtocolVersion::V5), Ok(connect));
+}
+
+#[test]
+fn decode_connect_missing_fields() {
+    let mut connect_bytes = connect_bytes();
+    let len = connect_bytes.len();
+    connect_bytes.truncate(len - 4); //no password
+    connect_bytes[1] -= 4;
+    assert_eq!(decode(&connect_bytes), Err(DecodeError::Truncated));
+}
+
+#[test]
+fn decode_publish_with_msg_id() {
+    let pub_bytes = vec![
+        0x3c, 0x0d, //fixed header
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,//topic name
+        0x00, 0x21, //message ID
+        'b' as u8, 'o' as u8, 'r' as u8, 'g' as u8, //payload
+        ];
+    assert_eq!(decode(&pub_bytes),
+               Ok(Packet::Publish(Publish { dup: true, qos: 2, retain: false, topic: "first".to_string(),
+                                            packet_id: Some(0x21), payload: b"borg".to_vec(),
+                                            properties: vec![] })));
+}
+
+#[test]
+fn decode_16_bit_packet_ids() {
+    round_trip(Packet::Publish(Publish { dup: false, qos: 1, retain: false, topic: "t".to_string(),
+                                         packet_id: Some(0xbeef), payload: vec![], properties: vec![] }));
+    round_trip(Packet::PubAck(PubAck::new(0x100)));
+    round_trip(Packet::PubRec(PubRec::new(0x1234)));
+    round_trip(Packet::PubRel(PubRel::new(0xff00)));
+    round_trip(Packet::PubComp(PubComp::new(0xffff)));
+    round_trip(Packet::SubAck(SubAck { packet_id: 0x1234, return_codes: vec![0], properties: vec![] }));
+    round_trip(Packet::UnsubAck(UnsubAck { packet_id: 0x4321, reason_codes: vec![], properties: vec![] }));
+
+    let mut bytes = vec![];
+    Packet::SubAck(SubAck { packet_id: 0x1234, return_codes: vec![1], properties: vec![] })
+        .encode(&mut bytes);
+    assert_eq!(bytes, vec![0x90, 3, 0x12, 0x34, 1]);
+
+    assert_eq!(decode(&[0x82, 6, 0x01, 0x02, 0, 1, 'a' as u8, 0]),
+               Ok(Packet::Subscribe(Subscribe {
+                   packet_id: 0x0102,
+                   topics: vec![SubscribeTopic::new("a", 0)],
+                   properties: vec![],
+               })));
+}
+
+#[test]
+fn decode_zero_packet_id() {
+    assert_eq!(decode(&[0x32, 5, 0, 1, 't' as u8, 0, 0]), Err(DecodeError::InvalidPacketId));
+    assert_eq!(decode(&[0x40, 2, 0, 0]), Err(DecodeError::InvalidPacketId));
+    assert_eq!(decode(&[0x50, 2, 0, 0]), Err(DecodeError::InvalidPacketId));
+    assert_eq!(decode(&[0x62, 2, 0, 0]), Err(DecodeError::InvalidPacketId));
+    assert_eq!(decode(&[0x70, 2, 0, 0]), Err(DecodeError::InvalidPacketId));
+    assert_eq!(decode(&[0x82, 6, 0, 0, 0, 1, 'a' as u8, 0]), Err(DecodeError::InvalidPacketId));
+    assert_eq!(decode(&[0x90, 3, 0, 0, 0]), Err(DecodeError::InvalidPacketId));
+    assert_eq!(decode(&[0xa2, 5, 0, 0, 0, 1, 'a' as u8]), Err(DecodeError::InvalidPacketId));
+    assert_eq!(decode(&[0xb0, 2, 0, 0]), Err(DecodeError::InvalidPacketId));
+
+    //QoS 0 publishes don't have one at all
+    assert_eq!(decode(&[0x30, 5, 0, 1, 't' as u8, 0, 0]),
+               Ok(Packet::Publish(Publish { dup: false, qos: 0, retain: false, topic: "t".to_string(), packet_id: None,
+                                            payload: vec![0, 0], properties: vec![] })));
+}
+
+#[test]
+fn decode_publish_flags() {
+    let pub_bytes = vec![0x3b, 5, 0, 1, 't' as u8, 0, 7];
+    assert_eq!(decode(&pub_bytes),
+               Ok(Packet::Publish(Publish { dup: true, qos: 1, retain: true, topic: "t".to_string(),
+                                            packet_id: Some(7), payload: vec![], properties: vec![] })));
+
+    let pub_bytes = vec![0x31, 4, 0, 1, 't' as u8, 9];
+    assert_eq!(decode(&pub_bytes),
+               Ok(Packet::Publish(Publish { dup: false, qos: 0, retain: true, topic: "t".to_string(),
+                                            packet_id: None, payload: vec![9], properties: vec![] })));
+}
+
+#[test]
+fn decode_publish_qos_3() {
+    assert_eq!(decode(&[0x36, 5, 0, 1, 't' as u8, 0, 7]), Err(DecodeError::InvalidQoS));
+}
+
+#[test]
+fn decode_subscribe_qos() {
+    assert_eq!(decode(&[0x82, 10, 0, 1, 0, 1, 'a' as u8, 1, 0, 1, 'b' as u8, 2]),
+               Ok(Packet::Subscribe(Subscribe {
+                   packet_id: 1,
+                   topics: vec![SubscribeTopic::new("a", 1),
+                                SubscribeTopic::new("b", 2)],
+                   properties: vec![],
+               })));
+    assert_eq!(decode(&[0x82, 6, 0, 1, 0, 1, 'a' as u8, 3]), Err(DecodeError::InvalidQoS));
+    assert_eq!(decode(&[0x82, 2, 0, 1]), Err(DecodeError::NoTopics));
+}
+
+#[test]
+fn decode_subscription_options() {
+    //no local, retain as published and retain handling 2 on top of QoS 1
+    assert_eq!(decode_version(&[0x82, 7, 0, 1, 0, 0, 1, 'a' as u8, 0x2d], ProtocolVersion::V5),
+               Ok(Packet::Subscribe(Subscribe {
+                   packet_id: 1,
+                   topics: vec![SubscribeTopic {
+                       no_local: true,
+                       retain_as_published: true,
+                       retain_handling: 2,
+                       ..SubscribeTopic::new("a", 1)
+                   }],
+                   properties: vec![],
+               })));
+    assert_eq!(decode_version(&[0x82, 7, 0, 1, 0, 0, 1, 'a' as u8, 0x30], ProtocolVersion::V5),
+               Err(DecodeError::InvalidSubscriptionOptions));
+    assert_eq!(decode_version(&[0x82, 7, 0, 1, 0, 0, 1, 'a' as u8, 0x41], ProtocolVersion::V5),
+               Err(DecodeError::ReservedFlagsSet));
+
+    //none of that before MQTT 5
+    assert_eq!(decode(&[0x82, 6, 0, 1, 0, 1, 'a' as u8, 0x05]), Err(DecodeError::ReservedFlagsSet));
+    assert_eq!(decode_version(&[0x8c, 6, 0, 1, 0, 1, 'a' as u8, 0x80], ProtocolVersion::V31),
+               Err(DecodeError::ReservedFlagsSet));
+}
+
+#[test]
+fn round_trip_subscription_options() {
+    let subscribe = Packet::Subscribe(Subscribe {
+        packet_id: 3,
+        topics: vec![SubscribeTopic { no_local: true, retain_handling: 1, ..SubscribeTopic::new("a/#", 2) }],
+        properties: vec![],
+    });
+    let mut bytes = vec![];
+    subscribe.encode_version(ProtocolVersion::V5, &mut bytes);
+    assert_eq!(decode_version(&bytes, ProtocolVersion::V5), Ok(subscribe));
+}
+
+#[test]
+fn decode_unsubscribe_needs_topics() {
+    assert_eq!(decode(&[0xa2, 5, 0, 1, 0, 1, 'a' as u8]),
+               Ok(Packet::Unsubscribe(Unsubscribe { packet_id: 1, topics: vec!["a".to_string()], properties: vec![] })));
+    assert_eq!(decode(&[0xa2, 2, 0, 1]), Err(DecodeError::NoTopics));
+}
+
+#[test]
+fn encode_connack_result() {
+    let encode = |result, version| {
+        let mut bytes = vec![];
+        Packet::ConnAck(ConnAck::new(result, version)).encode_version(version, &mut bytes);
+        bytes
+    };
+    assert_eq!(encode(Ok(true), ProtocolVersion::V311), vec![0x20, 2, 1, 0]);
+    assert_eq!(encode(Err(ConnectError::UnacceptableProtocolVersion), ProtocolVersion::V311), vec![0x20, 2, 0, 1]);
+    assert_eq!(encode(Err(ConnectError::IdentifierRejected), ProtocolVersion::V31), vec![0x20, 2, 0, 2]);
+    assert_eq!(encode(Err(ConnectError::ServerUnavailable), ProtocolVersion::V311), vec![0x20, 2, 0, 3]);
+    assert_eq!(encode(Err(ConnectError::BadUsernameOrPassword), ProtocolVersion::V311), vec![0x20, 2, 0, 4]);
+    assert_eq!(encode(Err(ConnectError::NotAuthorized), ProtocolVersion::V311), vec![0x20, 2, 0, 5]);
+    assert_eq!(encode(Ok(false), ProtocolVersion::V5), vec![0x20, 3, 0, 0, 0]);
+    assert_eq!(encode(Err(ConnectError::BadUsernameOrPassword), ProtocolVersion::V5), vec![0x20, 3, 0, 0x86, 0]);
+    assert_eq!(encode(Err(ConnectError::NotAuthorized), ProtocolVersion::V5), vec![0x20, 3, 0, 0x87, 0]);
+}
+
+#[test]
+fn encode_publish_flags() {
+    let mut bytes = vec![];
+    Packet::Publish(Publish { dup: true, qos: 2, retain: true, topic: "t".to_string(),
+                              packet_id: Some(7), payload: vec![], properties: vec![] })
+        .encode(&mut bytes);
+    assert_eq!(bytes, vec![0x3d, 5, 0, 1, 't' as u8, 0, 7]);
+}
+
+#[test]
+fn topic_names() {
+    assert_eq!(valid_topic_name("foo"), true);
+    assert_eq!(valid_topic_name("foo/bar"), true);
+    assert_eq!(valid_topic_name("/"), true);
+    assert_eq!(valid_topic_name("foo bar/ baz"), true);
+    assert_eq!(valid_topic_name(""), false);
+    assert_eq!(valid_topic_name("foo/+"), false);
+    assert_eq!(valid_topic_name("foo/#"), false);
+    assert_eq!(valid_topic_name("foo+"), false);
+    assert_eq!(valid_topic_name("foo\u{0}bar"), false);
+    assert_eq!(valid_topic_name(&"a".repeat(MAX_TOPIC_LENGTH)), true);
+    assert_eq!(valid_topic_name(&"a".repeat(MAX_TOPIC_LENGTH + 1)), false);
+}
+
+#[test]
+fn topic_filters() {
+    assert_eq!(valid_topic_filter("foo"), true);
+    assert_eq!(valid_topic_filter("foo/bar"), true);
+    assert_eq!(valid_topic_filter("#"), true);
+    assert_eq!(valid_topic_filter("+"), true);
+    assert_eq!(valid_topic_filter("foo/#"), true);
+    assert_eq!(valid_topic_filter("foo/+/bar"), true);
+    assert_eq!(valid_topic_filter("+/+/#"), true);
+    assert_eq!(valid_topic_filter("/"), true);
+    assert_eq!(valid_topic_filter(""), false);
+    assert_eq!(valid_topic_filter("finance#"), false);
+    assert_eq!(valid_topic_filter("finance/#/stock"), false);
+    assert_eq!(valid_topic_filter("finance/##"), false);
+    assert_eq!(valid_topic_filter("finance+"), false);
+    assert_eq!(valid_topic_filter("finance/+stock"), false);
+    assert_eq!(valid_topic_filter("foo\u{0}bar"), false);
+}
+
+#[test]
+fn decode_invalid_topics() {
+    assert_eq!(decode(&[0x30, 5, 0, 3, 'a' as u8, '/' as u8, '+' as u8]), Err(DecodeError::InvalidTopic));
+    assert_eq!(decode(&[0x30, 2, 0, 0]), Err(DecodeError::InvalidTopic));
+    assert_eq!(decode(&[0x30, 3, 0, 1, 0]), Err(DecodeError::DisallowedCharacter('\u{0}')));
+
+    let connect_bytes = vec![
+        0x10u8, 0x13, // fixed header
+        0x00, 0x04, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8,
+        0x04, // protocol version
+        0x06, // connection flags, clean session and will
+        0x00, 0x3c, // keepalive
+        0x00, 0x01, 'c' as u8, // client ID
+        0x00, 0x01, '#' as u8, // will topic
+        0x00, 0x01, 'm' as u8, // will msg
+        ];
+    assert_eq!(decode(&connect_bytes), Err(DecodeError::InvalidTopic));
+}
+
+#[cfg(test)]
+fn strict_string(bytes: &[u8]) -> Result<String, DecodeError> {
+    mqtt_string(bytes, NonCharacters::Reject)
+}
+
+#[test]
+fn mqtt_strings() {
+    assert_eq!(strict_string(b"foo"), Ok("foo".to_string()));
+    assert_eq!(strict_string(&[0xc3, 0xbc]), Ok("\u{fc}".to_string()));
+    assert_eq!(strict_string(&[0xf0, 0x9f, 0x98, 0x80]), Ok("\u{1f600}".to_string()));
+    assert_eq!(strict_string(&[0xef, 0xbb, 0xbf]), Ok("\u{feff}".to_string())); //BOM is fine
+    assert_eq!(strict_string(&[]), Ok("".to_string()));
+}
+
+#[test]
+fn mqtt_strings_invalid() {
+    assert_eq!(strict_string(&[0xc3, 0x28]), Err(DecodeError::InvalidUtf8));
+    assert_eq!(strict_string(&[0xed, 0xa0, 0x80]), Err(DecodeError::InvalidUtf8)); //U+D800
+    assert_eq!(strict_string(&[0xed, 0xbf, 0xbf]), Err(DecodeError::InvalidUtf8)); //U+DFFF
+    assert_eq!(strict_string(&[0xc0, 0x80]), Err(DecodeError::InvalidUtf8)); //overlong U+0000
+    assert_eq!(strict_string(&['a' as u8, 0]), Err(DecodeError::DisallowedCharacter('\u{0}')));
+    assert_eq!(mqtt_string(&[0], NonCharacters::Allow), Err(DecodeError::DisallowedCharacter('\u{0}')));
+}
+
+#[test]
+fn mqtt_strings_non_characters() {
+    let non_characters: Vec<&[u8]> = vec![
+        &[0xef, 0xbf, 0xbf], //U+FFFF
+        &[0xef, 0xbf, 0xbe], //U+FFFE
+        &[0xef, 0xb7, 0x90], //U+FDD0
+        &[0xef, 0xb7, 0xaf], //U+FDEF
+        &[0xf0, 0x9f, 0xbf, 0xbe], //U+1FFFE
+        &[0xf4, 0x8f, 0xbf, 0xbf], //U+10FFFF
+        ];
+    for bytes in non_characters {
+        assert_eq!(mqtt_string(bytes, NonCharacters::Allow).is_ok(), true);
+        match mqtt_string(bytes, NonCharacters::Reject) {
+            Err(DecodeError::DisallowedCharacter(_)) => {}
+            other => panic!("Expected a disallowed character error for {:?}, got {:?}", bytes, other),
+        }
+    }
+
+    assert_eq!(strict_string(&[0xef, 0xb7, 0x8f]), Ok("\u{fdcf}".to_string()));
+    assert_eq!(strict_string(&[0xef, 0xb7, 0xb0]), Ok("\u{fdf0}".to_string()));
+}
+
+#[test]
+fn decode_non_characters() {
+    let pub_bytes = vec![0x30, 5, 0, 3, 0xef, 0xbf, 0xbf];
+    assert_eq!(decode(&pub_bytes).is_ok(), true);
+    assert_eq!(decode_with(&pub_bytes, ProtocolVersion::V311, NonCharacters::Reject),
+               Err(DecodeError::DisallowedCharacter('\u{ffff}')));
+
+    //properties too
+    let pub_bytes = vec![0x30, 11, 0, 1, 't' as u8, 7, 0x26, 0, 1, 'k' as u8, 0, 1, 0];
+    assert_eq!(decode_version(&pub_bytes, ProtocolVersion::V5),
+               Err(DecodeError::DisallowedCharacter('\u{0}')));
+}
+
+#[test]
+fn decode_truncated() {
+    assert_eq!(decode(&[]), Err(DecodeError::Truncated));
+    assert_eq!(decode(&[0xc0]), Err(DecodeError::Truncated));
+    assert_eq!(decode(&[0x40, 2, 0]), Err(DecodeError::Truncated));
+    assert_eq!(decode(&[0x40, 1, 0]), Err(DecodeError::Truncated));
+    assert_eq!(decode(&[0x30, 3, 0, 5, 'f' as u8]), Err(DecodeError::Truncated));
+    assert_eq!(decode(&[0x82, 3, 0, 1, 0]), Err(DecodeError::Truncated));
+}
+
+#[test]
+fn decode_unknown_type() {
+    assert_eq!(decode(&[0x00, 0]), Err(DecodeError::UnknownPacketType(0)));
+    assert_eq!(decode(&[0xf0, 0]), Err(DecodeError::UnknownPacketType(0xf)));
+}
+
+#[test]
+fn decode_reserved_flags() {
+    assert_eq!(decode(&[0xc1, 0]), Err(DecodeError::ReservedFlagsSet));
+    assert_eq!(decode(&[0xe8, 0]), Err(DecodeError::ReservedFlagsSet));
+    assert_eq!(decode(&[0x42, 2, 0, 1]), Err(DecodeError::ReservedFlagsSet));
+    assert_eq!(decode(&[0x12, 0]), Err(DecodeError::ReservedFlagsSet));
+}
+
+#[test]
+fn decode_reserved_flags_0b0010() {
+    let pubrel = [0x6f, 2, 0, 1];
+    let subscribe = [0x8c, 6, 0, 1, 0, 1, 'a' as u8, 0];
+    let unsubscribe = [0xa0, 5, 0, 1, 0, 1, 'a' as u8];
+    for version in &[ProtocolVersion::V311, ProtocolVersion::V5] {
+        assert_eq!(decode_version(&pubrel, *version), Err(DecodeError::ReservedFlagsSet));
+        assert_eq!(decode_version(&subscribe, *version), Err(DecodeError::ReservedFlagsSet));
+        assert_eq!(decode_version(&unsubscribe, *version), Err(DecodeError::ReservedFlagsSet));
+    }
+
+    //3.1 clients get away with it
+    assert!(decode_version(&pubrel, ProtocolVersion::V31).is_ok());
+    assert!(decode_version(&subscribe, ProtocolVersion::V31).is_ok());
+    assert!(decode_version(&unsubscribe, ProtocolVersion::V31).is_ok());
+}
+
+#[test]
+fn decode_invalid_utf8() {
+    let pub_bytes = vec![
+        0x30, 0x06, //fixed header
+        0x00, 0x02, 0xc3, 0x28, //topic name
+        1, 2, //payload
+        ];
+    assert_eq!(decode(&pub_bytes), Err(DecodeError::InvalidUtf8));
+}
+
+#[test]
+fn encode_connect_fixture() {
+    let mut bytes = vec![];
+    decode(&connect_bytes()).expect("Could not decode connect").encode(&mut bytes);
+    assert_eq!(bytes, connect_bytes());
+}
+
+#[test]
+fn encode_round_trip() {
+    round_trip(Packet::ConnAck(ConnAck { session_present: true, return_code: 0, properties: vec![] }));
+    round_trip(Packet::Publish(Publish::new("foo/bar", &[1, 2, 3])));
+    round_trip(Packet::Publish(Publish { dup: false, qos: 1, retain: true, topic: "foo".to_string(),
+                                         packet_id: Some(1234), payload: vec![], properties: vec![] }));
+    round_trip(Packet::PubAck(PubAck::new(7)));
+    round_trip(Packet::PubRec(PubRec::new(8)));
+    round_trip(Packet::PubRel(PubRel::new(9)));
+    round_trip(Packet::PubComp(PubComp::new(10)));
+    round_trip(Packet::Subscribe(Subscribe {
+        packet_id: 11,
+        topics: vec![SubscribeTopic::new("first", 1),
+                     SubscribeTopic::new("second/#", 2)],
+        properties: vec![],
+    }));
+    round_trip(Packet::SubAck(SubAck { packet_id: 12, return_codes: vec![0, 1, 0x80], properties: vec![] }));
+    round_trip(Packet::Unsubscribe(Unsubscribe {
+        packet_id: 13,
+        topics: vec!["first".to_string(), "second/#".to_string()],
+        properties: vec![],
+    }));
+    round_trip(Packet::UnsubAck(UnsubAck { packet_id: 14, reason_codes: vec![], properties: vec![] }));
+    round_trip(Packet::PingReq(PingReq));
+    round_trip(Packet::PingResp(PingResp));
+    round_trip(Packet::Disconnect(Disconnect::new()));
+}
+
+#[test]
+fn encode_large_publish() {
+    let publish = Packet::Publish(Publish::new("big", &vec![7; 20000]));
+    let mut bytes = vec![];
+    publish.encode(&mut bytes);
+    assert_eq!(&bytes[0 .. 4], &[0x30, 0xa5, 0x9c, 0x01]); //20005 bytes remaining
+    assert_eq!(bytes.len(), 20009);
+    assert_eq!(decode(&bytes), Ok(publish));
+}
+
+#[test]
+fn encode_ping_resp() {
+    let mut bytes = vec![];
+    Packet::PingResp(PingResp).encode(&mut bytes);
+    assert_eq!(bytes, vec![0xd0, 0]);
+}
+
+#[cfg(test)]
 fn connect_bytes() -> Vec<u8> {
     vec!(
         0x10u8, 0x2a, // fixed header
@@ -43,100 +1928,213 @@ fn connect_bytes() -> Vec<u8> {
 #[test]
 fn connect_type() {
     let connect_bytes = connect_bytes();
-    assert_eq!(message_type(&connect_bytes), MqttType::Connect);
+    assert_eq!(message_type(&connect_bytes), Ok(MqttType::Connect));
 }
 
 #[test]
 fn ping_type() {
     let ping_bytes = &[0xc0u8, 0][0..];
-    assert_eq!(message_type(ping_bytes), MqttType::PingReq);
+    assert_eq!(message_type(ping_bytes), Ok(MqttType::PingReq));
 }
 
 #[test]
 fn subscribe_type() {
     let subscribe_header = &[0x80, 0][0..];
-    assert_eq!(message_type(subscribe_header), MqttType::Subscribe);
+    assert_eq!(message_type(subscribe_header), Ok(MqttType::Subscribe));
 }
 
 #[test]
 fn suback_type() {
     let subscribe_header = &[0x90, 3, 0, 7, 1][0..];
-    assert_eq!(message_type(subscribe_header), MqttType::SubAck);
+    assert_eq!(message_type(subscribe_header), Ok(MqttType::SubAck));
 }
 
 #[test]
 fn disconnect_type() {
     let bytes = &[224, 0][0..];
-    assert_eq!(message_type(bytes), MqttType::Disconnect);
+    assert_eq!(message_type(bytes), Ok(MqttType::Disconnect));
 
 }
 
-pub fn remaining_length(bytes: &[u8]) -> usize {
-    if bytes.len() < 2 {
-        return 0;
-    }
-
-    let bytes = &bytes[1..]; //skip half of the fixed header
+pub const MAX_REMAINING_LENGTH: usize = 268_435_455;
 
-    //algorithm straight from the MQTT spec
+//a variable byte integer and how many bytes it takes up
+fn variable_byte_integer(bytes: &[u8]) -> Result<(usize, usize), DecodeError> {
+    //algorithm straight from the MQTT spec, it's at most 4 bytes long
     let mut multiplier: usize = 1;
     let mut value: usize = 0;
-    let mut digit: usize = bytes[0] as usize;
-
-    loop { // do-while would be cleaner...
-        value += (digit & 127) * multiplier;
-        multiplier *= 128;
 
+    for (i, digit) in bytes.iter().take(4).enumerate() {
+        value += (*digit as usize & 127) * multiplier;
         if (digit & 128) == 0 {
-            break;
+            return Ok((value, i + 1));
         }
+        multiplier *= 128;
+    }
 
-        if bytes.len() > 1 {
-            digit = bytes[1..][0] as usize;
-        }
+    if bytes.len() >= 4 {
+        Err(DecodeError::MalformedRemainingLength) //a fifth byte would be needed
+    } else {
+        Err(DecodeError::Truncated)
+    }
+}
+
+//value of the remaining length field and how many bytes it takes up,
+//given bytes starting at the beginning of the fixed header
+fn remaining_length_field(bytes: &[u8]) -> Result<(usize, usize), DecodeError> {
+    if bytes.is_empty() {
+        return Err(DecodeError::Truncated);
     }
+    variable_byte_integer(&bytes[1..])
+}
+
+//the first byte plus however many bytes the remaining length took
+pub fn fixed_header_length(bytes: &[u8]) -> Result<usize, DecodeError> {
+    remaining_length_field(bytes).map(|(_, len)| 1 + len)
+}
 
-    value
+pub fn encode_remaining_length(bytes: &mut Vec<u8>, mut len: usize) {
+    assert!(len <= MAX_REMAINING_LENGTH, "Remaining length {} is too large to encode", len);
+
+    loop {
+        let mut digit = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            digit |= 0x80;
+        }
+        bytes.push(digit);
+        if len == 0 {
+            break;
+        }
+    }
 }
 
 #[test]
 fn connect_len() {
-    assert_eq!(remaining_length(&connect_bytes()), 42);
+    assert_eq!(remaining_length(&connect_bytes()), Ok(42));
 }
 
 #[test]
 fn ping_len() {
     let ping_bytes = &[0xc0u8, 0][0..];
-    assert_eq!(remaining_length(&ping_bytes), 0);
+    assert_eq!(remaining_length(&ping_bytes), Ok(0));
 }
 
 #[test]
 fn msg_lens() {
-    assert_eq!(remaining_length(&[]), 0);
-    assert_eq!(remaining_length(&[0x15, 5]), 5);
-    assert_eq!(remaining_length(&[0x27, 7]), 7);
-    assert_eq!(remaining_length(&[0x12, 0xc1, 0x02]), 321);
-    assert_eq!(remaining_length(&[0x12, 0x83, 0x02]), 259);
-    //assert_eq!(remaining_length(&[0x12, 0x85, 0x80, 0x01]), 2097157);
+    assert_eq!(remaining_length(&[]), Err(DecodeError::Truncated));
+    assert_eq!(remaining_length(&[0x15, 5]), Ok(5));
+    assert_eq!(remaining_length(&[0x27, 7]), Ok(7));
+    assert_eq!(remaining_length(&[0x12, 0xc1, 0x02]), Ok(321));
+    assert_eq!(remaining_length(&[0x12, 0x83, 0x02]), Ok(259));
+    assert_eq!(remaining_length(&[0x12, 0x85, 0x80, 0x01]), Ok(16389));
+    assert_eq!(remaining_length(&[0x12, 0x85, 0x80, 0x80, 0x01]), Ok(2097157));
+    assert_eq!(remaining_length(&[0x12, 0xff, 0xff, 0xff, 0x7f]), Ok(MAX_REMAINING_LENGTH));
+}
+
+#[test]
+fn malformed_len() {
+    assert_eq!(remaining_length(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x7f]),
+               Err(DecodeError::MalformedRemainingLength));
+    assert_eq!(remaining_length(&[0x30, 0x80, 0x80, 0x80, 0x80]),
+               Err(DecodeError::MalformedRemainingLength));
+}
+
+#[test]
+fn truncated_len() {
+    assert_eq!(remaining_length(&[0x30]), Err(DecodeError::Truncated));
+    assert_eq!(remaining_length(&[0x30, 0x80]), Err(DecodeError::Truncated));
+    assert_eq!(remaining_length(&[0x30, 0xff, 0xff, 0xff]), Err(DecodeError::Truncated));
+}
+
+#[test]
+fn header_lens() {
+    assert_eq!(fixed_header_length(&[0xc0, 0]), Ok(2));
+    assert_eq!(fixed_header_length(&[0x12, 0xc1, 0x02]), Ok(3));
+    assert_eq!(fixed_header_length(&[0x12, 0x85, 0x80, 0x01]), Ok(4));
+    assert_eq!(fixed_header_length(&[0x12, 0xff, 0xff, 0xff, 0x7f]), Ok(5));
+    assert_eq!(total_length(&[0x12, 0xc1, 0x02]), Ok(324));
+}
+
+#[cfg(test)]
+fn remaining_length(bytes: &[u8]) -> Result<usize, DecodeError> {
+    remaining_length_field(bytes).map(|(value, _)| value)
+}
+
+#[cfg(test)]
+fn encoded_len(len: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    encode_remaining_length(&mut bytes, len);
+    bytes
+}
+
+#[test]
+fn encode_lens() {
+    assert_eq!(encoded_len(0), vec![0]);
+    assert_eq!(encoded_len(127), vec![0x7f]);
+    assert_eq!(encoded_len(128), vec![0x80, 0x01]);
+    assert_eq!(encoded_len(321), vec![0xc1, 0x02]);
+    assert_eq!(encoded_len(16383), vec![0xff, 0x7f]);
+    assert_eq!(encoded_len(16384), vec![0x80, 0x80, 0x01]);
+    assert_eq!(encoded_len(2097151), vec![0xff, 0xff, 0x7f]);
+    assert_eq!(encoded_len(2097157), vec![0x85, 0x80, 0x80, 0x01]);
+    assert_eq!(encoded_len(MAX_REMAINING_LENGTH), vec![0xff, 0xff, 0xff, 0x7f]);
+}
+
+#[test]
+fn encode_decode_lens() {
+    for &len in &[0, 1, 127, 128, 300, 16383, 16384, 2097151, 2097152, MAX_REMAINING_LENGTH] {
+        let mut bytes = vec![0x30];
+        encode_remaining_length(&mut bytes, len);
+        assert_eq!(remaining_length(&bytes), Ok(len));
+        assert_eq!(fixed_header_length(&bytes), Ok(bytes.len()));
+    }
+}
+
+#[test]
+#[should_panic]
+fn encode_len_too_large() {
+    encoded_len(MAX_REMAINING_LENGTH + 1);
 }
 
 
-pub fn subscribe_msg_id(bytes: &[u8]) -> u16 {
-    bytes[3] as u16
+#[cfg(test)]
+fn subscribe_msg_id(bytes: &[u8]) -> Result<u16, DecodeError> {
+    //3.1 fixtures, which don't care about the fixed header flags
+    match decode_version(bytes, ProtocolVersion::V31)? {
+        Packet::Subscribe(subscribe) => Ok(subscribe.packet_id),
+        other => panic!("Expected SUBSCRIBE, got {:?}", other),
+    }
 }
 
 #[test]
 fn subscribe_msg_id_happy() {
-    assert_eq!(subscribe_msg_id(&[0x8cu8, 3, 0, 33]), 33);
-    assert_eq!(subscribe_msg_id(&[0x8cu8, 3, 0, 21]), 21);
+    assert_eq!(subscribe_msg_id(&[0x8cu8, 5, 0, 33, 0, 0, 0]), Ok(33));
+    assert_eq!(subscribe_msg_id(&[0x8cu8, 5, 0, 21, 0, 0, 0]), Ok(21));
+}
+
+#[test]
+fn subscribe_msg_id_16_bits() {
+    assert_eq!(subscribe_msg_id(&[0x8cu8, 5, 1, 0, 0, 0, 0]), Ok(256));
+    assert_eq!(subscribe_msg_id(&[0x8cu8, 5, 0xff, 0xff, 0, 0, 0]), Ok(0xffff));
+}
+
+#[test]
+fn subscribe_msg_id_truncated() {
+    assert_eq!(subscribe_msg_id(&[0x8cu8, 1, 0]), Err(DecodeError::Truncated));
 }
 
-pub fn publish_topic(bytes: &[u8]) -> String {
-    //only works when there's no msg id
-    let topic_len = ((bytes[2] as u16) << 8) + bytes[3] as u16;
-    let topic_len = topic_len as usize;
-    String::from_utf8(bytes[4 .. 4 + topic_len].to_vec())
-        .expect("Could not convert publish topic to vec")
+#[test]
+fn subscribe_msg_id_zero() {
+    assert_eq!(subscribe_msg_id(&[0x8cu8, 5, 0, 0, 0, 0, 0]), Err(DecodeError::InvalidPacketId));
+}
+
+#[cfg(test)]
+fn decode_publish(bytes: &[u8]) -> Result<Publish, DecodeError> {
+    match decode(bytes)? {
+        Packet::Publish(publish) => Ok(publish),
+        other => panic!("Expected PUBLISH, got {:?}", other),
+    }
 }
 
 #[test]
@@ -147,18 +2145,16 @@ fn test_get_topic_with_msg_id() {
         0x00, 0x21, //message ID
         'b' as u8, 'o' as u8, 'r' as u8, 'g' as u8, //payload
         ];
-    assert_eq!(publish_topic(&pub_bytes[..]), "first");
+    assert_eq!(decode_publish(&pub_bytes[..]).map(|p| p.topic), Ok("first".to_string()));
 }
 
-
-pub fn publish_payload(bytes: &[u8]) -> &[u8] {
-    let topic_len = publish_topic(bytes).len();
-    let mut start = HEADER_LEN + topic_len + 2;
-    if (bytes[0] & 0x06) != 0 {
-        start += 2;
-    }
-
-    &bytes[start ..]
+#[test]
+fn test_get_topic_bad_length() {
+    let pub_bytes = vec![
+        0x30, 0x04, //fixed header
+        0x00, 0x05, 'f' as u8, 'i' as u8, //topic name that isn't all there
+        ];
+    assert_eq!(decode_publish(&pub_bytes[..]), Err(DecodeError::Truncated));
 }
 
 #[test]
@@ -169,10 +2165,9 @@ fn test_get_payload_with_msg_id() {
         0x00, 0x21, //message ID
         1, 2, 3, 4, //payload
         ];
-    assert_eq!(publish_payload(&pub_bytes[..]).to_vec(), vec![1, 2, 3, 4]);
+    assert_eq!(decode_publish(&pub_bytes[..]).map(|p| p.payload), Ok(vec![1u8, 2, 3, 4]));
 }
 
-
 #[test]
 fn test_get_payload_no_msg_id() {
     let pub_bytes = vec![
@@ -180,23 +2175,15 @@ fn test_get_payload_no_msg_id() {
         0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,//topic name
         9, 8, 7, //payload
         ];
-    assert_eq!(publish_payload(&pub_bytes[..]).to_vec(), vec![9, 8, 7]);
+    assert_eq!(decode_publish(&pub_bytes[..]).map(|p| p.payload), Ok(vec![9u8, 8, 7]));
 }
 
-
-pub fn subscribe_topics(bytes: &[u8]) -> Vec<String> {
-    let start = HEADER_LEN + 2; // final 2 for msg_id
-    let mut res = vec![];
-    let mut slice = &bytes[start .. ];
-    while slice.len() > 0 {
-        let topic_len = (((slice[0] as u16) << 8) + slice[1] as u16) as usize;
-        let topic_slice = &slice[2 .. 2 + topic_len];
-        res.push(String::from_utf8(topic_slice.to_vec())
-                 .expect("Could not convert subscribe topic to vec"));
-        //first 2 for topic len, last 1 for qos
-        slice = &slice[2 + topic_len + 1 .. ];
+#[cfg(test)]
+fn subscribe_topics(bytes: &[u8]) -> Result<Vec<String>, DecodeError> {
+    match decode_version(bytes, ProtocolVersion::V31)? {
+        Packet::Subscribe(subscribe) => Ok(subscribe.topics.into_iter().map(|t| t.topic).collect()),
+        other => panic!("Expected SUBSCRIBE, got {:?}", other),
     }
-    res
 }
 
 #[test]
@@ -209,7 +2196,7 @@ fn test_subscribe_topics1() {
         0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,
         0x02, //qos
         ];
-    assert_eq!(subscribe_topics(&sub_bytes[..]), vec!["first".to_string(), "second".to_string()]);
+    assert_eq!(subscribe_topics(&sub_bytes[..]), Ok(vec!["first".to_string(), "second".to_string()]));
 }
 
 #[test]
@@ -222,10 +2209,21 @@ fn test_subscribe_topics2() {
         0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,
         0x02, //qos
         ];
-    assert_eq!(subscribe_topics(&sub_bytes[..]), vec!["firs".to_string(), "second".to_string()]);
+    assert_eq!(subscribe_topics(&sub_bytes[..]), Ok(vec!["firs".to_string(), "second".to_string()]));
+}
+
+#[test]
+fn test_subscribe_topics_missing_qos() {
+    let sub_bytes = vec![
+        0x8b, 0x09, //fixed header
+        0x00, 0x21, //message ID
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,
+        ];
+    assert_eq!(subscribe_topics(&sub_bytes[..]), Err(DecodeError::Truncated));
 }
 
 
-pub fn total_length(bytes: &[u8]) -> usize {
-    return remaining_length(bytes) + HEADER_LEN;
+pub fn total_length(bytes: &[u8]) -> Result<usize, DecodeError> {
+    let (value, len) = remaining_length_field(bytes)?;
+    Ok(1 + len + value)
 }
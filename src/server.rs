@@ -3,116 +3,962 @@ use broker;
 
 use std::rc::{Rc};
 use std::cell::{RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+//whatever is on the other end of a connection, the server just writes bytes to it
+//and hangs up on it when it's no longer wanted
+pub trait Client {
+    fn send(&mut self, bytes: &[u8]);
+    fn close(&mut self);
+}
+
+pub struct Server<T: Client> {
+    broker: broker::Broker<Session<T>>,
+    connections: HashMap<usize, Connection<T>>,
+    connect_timeout: Duration, //how long a client gets to send CONNECT
+    connects: HashMap<usize, message::Connect>, //what each client said when it connected
+    sessions: HashMap<usize, Rc<RefCell<Session<T>>>>,
+    persistent: HashMap<String, Rc<RefCell<Session<T>>>>, //by client id, outlive the connection
+    clients: HashMap<String, Rc<RefCell<T>>>, //who's connected with each client id
+    wills: Vec<PendingWill>, //waiting out their MQTT 5 will delay interval
+    keep_alives: HashMap<usize, KeepAlive<T>>, //for clients that asked for one
+    server_keep_alive: Option<u16>, //what MQTT 5 clients get told to use instead of their own
+    credentials: Option<(String, Vec<u8>)>, //the username and password clients need, if any
+    max_clients: Option<usize>,
+    max_queued: usize, //messages kept for each session before the oldest get dropped
+    session_expiry: u32, //how long 3.1.1 sessions that aren't clean are kept, in seconds
+    assigned_ids: u64, //how many client ids the server has made up for MQTT 5 clients without one
+    non_characters: message::NonCharacters,
+}
+
+//where each connection is at, clients have to CONNECT before anything else
+//and only the once
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum State {
+    AwaitingConnect,
+    Connected,
+    Disconnecting, //the server hung up, nothing else gets processed
+}
+
+struct Connection<T: Client> {
+    client: Rc<RefCell<T>>,
+    state: State,
+    accepted: Instant,
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_MAX_QUEUED: usize = 1000;
+pub const DEFAULT_SESSION_EXPIRY_SECS: u32 = 24 * 60 * 60;
+
+//clients that go quiet for longer than this get disconnected
+struct KeepAlive<T: Client> {
+    client: Rc<RefCell<T>>,
+    timeout: Duration, //one and a half times the keep alive
+    last_heard: Instant,
+}
+
+//a will that gets published at some point unless its client comes back first
+struct PendingWill {
+    client_id: String,
+    publish: message::Publish,
+    due: Instant,
+}
+
+//how long to wait for a client to acknowledge a message before sending it again
+pub const RETRANSMIT_SECS: u64 = 20;
+
+//what the broker delivers to on behalf of a client, encoding for
+//whichever protocol version it connected with
+struct Session<T: Client> {
+    client: Option<Rc<RefCell<T>>>, //None while a persistent session waits for its client
+    version: message::ProtocolVersion,
+    last_packet_id: u16, //the last one used for a message sent to the client
+    inflight: Vec<Inflight>, //sent to the client but not acknowledged yet, oldest first
+    unreleased: HashSet<u16>, //QoS 2 messages from the client still waiting for PUBREL
+    queued: VecDeque<message::Publish>, //QoS 1 and 2 messages that arrived while disconnected or with too many in flight
+    max_queued: usize, //the oldest queued messages are dropped to stay under this
+    receive_maximum: usize, //how many QoS 1 and 2 messages the client will have unacknowledged at once
+    expiry: Duration, //how long to keep the session after disconnecting, 0 for not at all
+    disconnected: Option<Instant>,
+}
+
+struct Inflight {
+    publish: message::Publish,
+    released: bool, //QoS 2 only, PUBREC came back and PUBREL went out
+    sent: Instant,
+}
+
+impl<T: Client> Session<T> {
+    fn new(client: Rc<RefCell<T>>, version: message::ProtocolVersion) -> Self {
+        Session {
+            client: Some(client),
+            version: version,
+            last_packet_id: 0,
+            inflight: vec![],
+            unreleased: HashSet::new(),
+            queued: VecDeque::new(),
+            max_queued: DEFAULT_MAX_QUEUED,
+            receive_maximum: u16::max_value() as usize,
+            expiry: Duration::from_secs(0),
+            disconnected: None,
+        }
+    }
+
+    fn send(&self, packet: message::Packet) {
+        if let Some(ref client) = self.client {
+            send(client, self.version, packet);
+        }
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.expiry > Duration::from_secs(0)
+    }
+
+    fn detach(&mut self, now: Instant) {
+        self.client = None;
+        self.disconnected = Some(now);
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.disconnected {
+            Some(disconnected) => now.duration_since(disconnected) >= self.expiry,
+            None => false,
+        }
+    }
+
+    //the client is back: anything it didn't acknowledge goes out again, then whatever
+    //was published while it was away
+    fn resume(&mut self, client: Rc<RefCell<T>>, version: message::ProtocolVersion) {
+        self.client = Some(client.clone());
+        self.version = version;
+        self.disconnected = None;
+
+        let now = Instant::now();
+        for inflight in self.inflight.iter_mut() {
+            Self::resend(&client, version, inflight, now);
+        }
+
+        self.send_queued();
+    }
+
+    //None if the client can't take any more messages until it acknowledges some
+    fn next_packet_id(&mut self) -> Option<u16> {
+        //receive_maximum is never more than there are packet identifiers, so
+        //below it there's always a free one to be found
+        if self.inflight.len() >= self.receive_maximum {
+            return None;
+        }
+
+        loop {
+            //0 isn't a valid packet identifier
+            self.last_packet_id = self.last_packet_id.wrapping_add(1).max(1);
+            let packet_id = Some(self.last_packet_id);
+            if !self.inflight.iter().any(|i| i.publish.packet_id == packet_id) {
+                return packet_id;
+            }
+        }
+    }
+
+    fn remove_inflight(&mut self, packet_id: u16, qos: u8) {
+        self.inflight.retain(|i| i.publish.packet_id != Some(packet_id) || i.publish.qos != qos);
+        self.send_queued();
+    }
+
+    //oldest first, for as long as the client has room for them
+    //a client that stays away or never acknowledges anything doesn't get to use up
+    //all the memory, it loses the oldest messages instead
+    fn queue(&mut self, publish: &message::Publish) {
+        if self.max_queued == 0 {
+            return;
+        }
+        while self.queued.len() >= self.max_queued {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(publish.clone());
+    }
+
+    fn send_queued(&mut self) {
+        while self.client.is_some() && !self.queued.is_empty() && self.inflight.len() < self.receive_maximum {
+            let publish = self.queued.pop_front().expect("queued message disappeared");
+            broker::Subscriber::new_message(self, &publish);
+        }
+    }
+
+    //PUBACK, the client has the QoS 1 message
+    fn acknowledged(&mut self, packet_id: u16) {
+        self.remove_inflight(packet_id, 1);
+    }
+
+    //PUBREC, the client has the QoS 2 message and it's time to release it
+    fn received(&mut self, pubrec: &message::PubRec) {
+        //MQTT 5 clients can turn messages down, in which case that's the end of it
+        if pubrec.reason_code >= message::UNSPECIFIED_ERROR {
+            self.remove_inflight(pubrec.packet_id, 2);
+            return;
+        }
+
+        let now = Instant::now();
+        let mut reason_code = message::PACKET_IDENTIFIER_NOT_FOUND;
+        for inflight in self.inflight.iter_mut() {
+            if inflight.publish.packet_id == Some(pubrec.packet_id) && inflight.publish.qos == 2 {
+                inflight.released = true;
+                inflight.sent = now;
+                reason_code = message::SUCCESS;
+            }
+        }
+
+        self.send(message::Packet::PubRel(message::PubRel {
+            packet_id: pubrec.packet_id,
+            reason_code: reason_code,
+            properties: vec![],
+        }));
+    }
+
+    //PUBCOMP, the end of the road for a QoS 2 message
+    fn completed(&mut self, packet_id: u16) {
+        self.remove_inflight(packet_id, 2);
+    }
+
+    //a QoS 2 message from the client, returns false if it's one that was already
+    //passed on and the client is just sending it again
+    fn incoming(&mut self, packet_id: u16) -> bool {
+        self.unreleased.insert(packet_id)
+    }
+
+    //PUBREL from the client, returns false if it's not a packet identifier we know of
+    fn release(&mut self, packet_id: u16) -> bool {
+        self.unreleased.remove(&packet_id)
+    }
 
-pub struct Server<T: broker::Subscriber> {
-    broker: broker::Broker<T>,
+    //MQTT 5 only allows resending when the client reconnects, 3.1 and 3.1.1 don't mind
+    fn retransmit(&mut self, now: Instant) {
+        if self.version == message::ProtocolVersion::V5 {
+            return;
+        }
+
+        let client = match self.client {
+            Some(ref client) => client.clone(),
+            None => return, //it all gets resent when the client reconnects
+        };
+
+        let timeout = Duration::from_secs(RETRANSMIT_SECS);
+        for inflight in self.inflight.iter_mut() {
+            if now.duration_since(inflight.sent) >= timeout {
+                Self::resend(&client, self.version, inflight, now);
+            }
+        }
+    }
+
+    fn resend(client: &Rc<RefCell<T>>, version: message::ProtocolVersion, inflight: &mut Inflight, now: Instant) {
+        inflight.sent = now;
+        if inflight.released {
+            let packet_id = inflight.publish.packet_id.expect("QoS 2 message without a packet id");
+            send(client, version, message::Packet::PubRel(message::PubRel::new(packet_id)));
+        } else {
+            inflight.publish.dup = true;
+            send(client, version, message::Packet::Publish(inflight.publish.clone()));
+        }
+    }
+}
+
+impl<T: Client> broker::Subscriber for Session<T> {
+    fn new_message(&mut self, publish: &message::Publish) {
+        if self.client.is_none() {
+            //QoS 0 means at most once, and for a disconnected client that's never
+            if publish.qos > 0 {
+                self.queue(publish);
+            }
+            return;
+        }
+
+        let packet_id = if publish.qos > 0 {
+            match self.next_packet_id() {
+                Some(packet_id) => Some(packet_id),
+                None => {
+                    //it goes out when one of the ones in flight is acknowledged
+                    self.queue(publish);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let publish = message::Publish { packet_id: packet_id, ..publish.clone() };
+        if publish.qos > 0 {
+            self.inflight.push(Inflight { publish: publish.clone(), released: false, sent: Instant::now() });
+        }
+        self.send(message::Packet::Publish(publish));
+    }
+}
+
+fn send<T: Client>(client: &Rc<RefCell<T>>, version: message::ProtocolVersion, packet: message::Packet) {
+    let mut bytes = vec![];
+    packet.encode_version(version, &mut bytes);
+    client.borrow_mut().send(&bytes);
+}
+
+//3.1.1 sessions last as long as the server is configured to keep them unless
+//they're clean, MQTT 5 clients say how long they want
+fn session_expiry(connect: &message::Connect, default: u32) -> Duration {
+    //a session nobody can ask for again might as well not be kept
+    if connect.client_id.is_empty() {
+        return Duration::from_secs(0);
+    }
+
+    let seconds = match connect.version {
+        message::ProtocolVersion::V5 => connect.properties.iter().filter_map(|p| match *p {
+            message::Property::SessionExpiryInterval(seconds) => Some(seconds),
+            _ => None,
+        }).next().unwrap_or(0),
+        _ if connect.clean_session => 0,
+        _ => default,
+    };
+    Duration::from_secs(seconds as u64)
+}
+
+//MQTT 5 clients can ask for fewer messages in flight, the others get as many
+//as there are packet identifiers
+fn receive_maximum(connect: &message::Connect) -> usize {
+    connect.properties.iter().filter_map(|p| match *p {
+        message::Property::ReceiveMaximum(maximum) => Some(maximum as usize),
+        _ => None,
+    }).next().unwrap_or(u16::max_value() as usize)
+}
+
+//clients are told apart by the address of the RefCell they share with the event loop
+fn client_key<T>(client: &Rc<RefCell<T>>) -> usize {
+    &**client as *const RefCell<T> as usize
 }
 
 
+#[cfg(test)]
 static CONNACK_OK : [u8; 4] = [32, 2, 0, 0];
+#[cfg(test)]
 static PING_RESP : [u8; 2] = [0xd0, 0];
 
 
-impl<T: broker::Subscriber> Server<T> {
+impl<T: Client> Server<T> {
     pub fn new(use_cache: bool) -> Self {
-        Server { broker: broker::Broker::new(use_cache) }
+        Server {
+            broker: broker::Broker::new(use_cache),
+            connections: HashMap::new(),
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            connects: HashMap::new(),
+            sessions: HashMap::new(),
+            persistent: HashMap::new(),
+            clients: HashMap::new(),
+            wills: vec![],
+            keep_alives: HashMap::new(),
+            server_keep_alive: None,
+            credentials: None,
+            max_clients: None,
+            max_queued: DEFAULT_MAX_QUEUED,
+            session_expiry: DEFAULT_SESSION_EXPIRY_SECS,
+            assigned_ids: 0,
+            non_characters: message::NonCharacters::Allow,
+        }
+    }
+
+    //disconnect clients that send strings with Unicode non-characters in them
+    pub fn reject_non_characters(&mut self) {
+        self.non_characters = message::NonCharacters::Reject;
+    }
+
+    //MQTT 5 clients are told to use this keep alive instead of the one they asked for
+    pub fn override_keep_alive(&mut self, seconds: u16) {
+        self.server_keep_alive = Some(seconds);
+    }
+
+    //sockets that don't send CONNECT within this long are closed
+    pub fn connect_timeout(&mut self, seconds: u64) {
+        self.connect_timeout = Duration::from_secs(seconds);
+    }
+
+    //clients that don't CONNECT with this username and password are turned away
+    pub fn require_credentials(&mut self, username: &str, password: &[u8]) {
+        self.credentials = Some((username.to_string(), password.to_vec()));
+    }
+
+    //the server is unavailable to any more clients than this
+    pub fn max_clients(&mut self, max_clients: usize) {
+        self.max_clients = Some(max_clients);
+    }
+
+    //messages waiting for a client beyond this many push out the oldest ones
+    pub fn max_queued(&mut self, max_queued: usize) {
+        self.max_queued = max_queued;
+    }
+
+    //how long 3.1.1 sessions are kept after their clients disconnect
+    pub fn session_expiry(&mut self, seconds: u32) {
+        self.session_expiry = seconds;
+    }
+
+    //to be called when a socket is accepted so that the connect timeout starts ticking
+    pub fn client_connected(&mut self, client: Rc<RefCell<T>>) {
+        self.connection(&client);
     }
 
     fn new_message(&mut self, client: Rc<RefCell<T>>, bytes: &[u8]) -> bool {
-        let message_type = message::message_type(bytes);
-        match message_type {
-            message::MqttType::Connect => {
-                let client = client.clone();
-                client.borrow_mut().new_message(&CONNACK_OK);
+        let version = self.version(&client);
+        if let Some(keep_alive) = self.keep_alives.get_mut(&client_key(&client)) {
+            keep_alive.last_heard = Instant::now();
+        }
+
+        let decoded = self.decode(&client, bytes);
+        if !self.is_expected(&client, &decoded) {
+            //if the server hung up it's closed once it's sent whatever it had to say
+            return self.connection(&client).state == State::Disconnecting;
+        }
+
+        match decoded {
+            Ok(message::Packet::Connect(mut connect)) => {
+                let version = connect.version;
+                if let Err(error) = self.accept(&connect) {
+                    println!("Refusing connection from {:?}: {:?}", connect.client_id, error);
+                    send(&client, version, message::Packet::ConnAck(message::ConnAck::new(Err(error), version)));
+                    self.hang_up(client);
+                    return true;
+                }
+
+                self.connection(&client).state = State::Connected;
+                let assigned_id = self.assign_client_id(&mut connect);
+                self.take_over(&client, &connect.client_id);
+                let session_present = self.attach_session(&client, &connect);
+                self.reconnected(&connect.client_id, session_present);
+                let mut properties = self.watch_keep_alive(&client, &connect);
+                properties.extend(assigned_id.map(message::Property::AssignedClientIdentifier));
+                self.connects.insert(client_key(&client), connect);
+                send(&client, version, message::Packet::ConnAck(message::ConnAck {
+                    properties: properties,
+                    ..message::ConnAck::new(Ok(session_present), version)
+                }));
+                if session_present {
+                    self.session(&client).borrow_mut().resume(client.clone(), version);
+                }
                 true
             }
-            message::MqttType::PingReq => {
-                let client = client.clone();
-                client.borrow_mut().new_message(&PING_RESP);
+            Ok(message::Packet::PingReq(_)) => {
+                send(&client, version, message::Packet::PingResp(message::PingResp));
                 true
             }
-            message::MqttType::Subscribe => {
-                for topic in message::subscribe_topics(bytes) {
-                    self.broker.subscribe(client.clone(), &topic[..]);
+            Ok(message::Packet::Subscribe(subscribe)) => {
+                let session = self.session(&client);
+                //one code per filter and in the same order, MQTT 5 can say what went wrong
+                let failure = match version {
+                    message::ProtocolVersion::V5 => message::TOPIC_FILTER_INVALID,
+                    _ => message::SUBACK_FAILURE,
+                };
+                let return_codes = subscribe.topics.iter().map(|topic| {
+                    if self.broker.subscribe(session.clone(), &topic.topic[..], topic.qos) {
+                        topic.qos
+                    } else {
+                        failure
+                    }
+                }).collect();
+
+                send(&client, version, message::Packet::SubAck(message::SubAck {
+                    packet_id: subscribe.packet_id,
+                    return_codes: return_codes,
+                    properties: vec![],
+                }));
+                true
+            }
+            Ok(message::Packet::Unsubscribe(unsubscribe)) => {
+                let session = self.session(&client);
+                let topics: Vec<&str> = unsubscribe.topics.iter().map(|t| &t[..]).collect();
+                let reason_codes = self.broker.unsubscribe(session, &topics).into_iter().map(|removed| {
+                    if removed { message::SUCCESS } else { message::NO_SUBSCRIPTION_EXISTED }
+                }).collect();
+
+                //the reason codes only get encoded for MQTT 5
+                send(&client, version, message::Packet::UnsubAck(message::UnsubAck {
+                    packet_id: unsubscribe.packet_id,
+                    reason_codes: reason_codes,
+                    properties: vec![],
+                }));
+                true
+            }
+            Ok(message::Packet::Publish(ref publish)) if publish.properties.iter().any(|p| match *p {
+                message::Property::SubscriptionIdentifier(_) => true,
+                _ => false,
+            }) => {
+                //only the server gets to say which subscription a message came through
+                self.disconnect(client, message::PROTOCOL_ERROR);
+                true
+            }
+            Ok(message::Packet::Publish(publish)) => {
+                match publish.packet_id {
+                    Some(packet_id) if publish.qos == 1 => {
+                        send(&client, version, message::Packet::PubAck(message::PubAck::new(packet_id)));
+                        self.broker.publish(&publish);
+                    }
+                    Some(packet_id) => {
+                        //QoS 2 gets passed on the first time, after that it's a retransmission
+                        let first_time = self.session(&client).borrow_mut().incoming(packet_id);
+                        send(&client, version, message::Packet::PubRec(message::PubRec::new(packet_id)));
+                        if first_time {
+                            self.broker.publish(&publish);
+                        }
+                    }
+                    None => self.broker.publish(&publish),
                 }
-
-                let msg_id = message::subscribe_msg_id(bytes);
-                let qos: u8 = 0;
-                let client = client.clone();
-                client.borrow_mut().new_message(&[0x90u8, 3, 0, msg_id as u8, qos][..]);
                 true
             }
-            message::MqttType::Publish => {
-                self.broker.publish(&message::publish_topic(bytes)[..], bytes);
+            Ok(message::Packet::PubAck(puback)) => {
+                if let Some(session) = self.sessions.get(&client_key(&client)) {
+                    session.borrow_mut().acknowledged(puback.packet_id);
+                }
+                true
+            }
+            Ok(message::Packet::PubRec(pubrec)) => {
+                if let Some(session) = self.sessions.get(&client_key(&client)) {
+                    session.borrow_mut().received(&pubrec);
+                }
+                true
+            }
+            Ok(message::Packet::PubRel(pubrel)) => {
+                let released = self.session(&client).borrow_mut().release(pubrel.packet_id);
+                send(&client, version, message::Packet::PubComp(message::PubComp {
+                    reason_code: if released { message::SUCCESS } else { message::PACKET_IDENTIFIER_NOT_FOUND },
+                    ..message::PubComp::new(pubrel.packet_id)
+                }));
+                true
+            }
+            Ok(message::Packet::PubComp(pubcomp)) => {
+                if let Some(session) = self.sessions.get(&client_key(&client)) {
+                    session.borrow_mut().completed(pubcomp.packet_id);
+                }
                 true
             }
-            message::MqttType::Disconnect => {
+            Ok(message::Packet::Disconnect(disconnect)) => {
+                self.connection(&client).state = State::Disconnecting;
+                //a clean disconnect means no will, unless an MQTT 5 client asks for it
+                if disconnect.reason_code != message::DISCONNECT_WITH_WILL_MESSAGE {
+                    if let Some(connect) = self.connects.get_mut(&client_key(&client)) {
+                        connect.will = None;
+                    }
+                }
                 false
             }
-            _ => {
-                println!("Bad message {:?}", &bytes);
+            Ok(message::Packet::ConnAck(_)) | Ok(message::Packet::SubAck(_)) | Ok(message::Packet::UnsubAck(_)) |
+            Ok(message::Packet::PingResp(_)) | Ok(message::Packet::Auth(_)) => {
+                //only the server sends these, a client that does is broken
+                self.disconnect(client, message::PROTOCOL_ERROR);
+                true
+            }
+            Err(message::DecodeError::UnacceptableProtocolVersion) => {
+                //we can't know what format they want so answer the 3.1.1 way
+                let error = message::ConnectError::UnacceptableProtocolVersion;
+                let mut connack = vec![];
+                message::Packet::ConnAck(message::ConnAck::new(Err(error), message::ProtocolVersion::V311))
+                    .encode(&mut connack);
+                client.borrow_mut().send(&connack);
+                self.hang_up(client);
                 true
             }
+            Err(error) => {
+                println!("Could not decode message ({:?}), closing connection: {:?}", error, &bytes);
+                self.disconnect(client, message::MALFORMED_PACKET);
+                true
+            }
+        }
+    }
+
+    //whether to let a client in, and if not what to tell it
+    fn accept(&self, connect: &message::Connect) -> Result<(), message::ConnectError> {
+        let valid_client_id = match connect.version {
+            //3.1 had a limit on how long client ids could be
+            message::ProtocolVersion::V31 => !connect.client_id.is_empty() && connect.client_id.len() <= 23,
+            //there's no asking for an empty id's session back
+            message::ProtocolVersion::V311 => !connect.client_id.is_empty() || connect.clean_session,
+            message::ProtocolVersion::V5 => true,
+        };
+        if !valid_client_id {
+            return Err(message::ConnectError::IdentifierRejected);
+        }
+
+        if let Some((ref username, ref password)) = self.credentials {
+            match (&connect.username, &connect.password) {
+                (&None, _) => return Err(message::ConnectError::NotAuthorized),
+                (&Some(ref u), &Some(ref p)) if u == username && p == password => {}
+                _ => return Err(message::ConnectError::BadUsernameOrPassword),
+            }
+        }
+
+        if let Some(max_clients) = self.max_clients {
+            //somebody taking over a session isn't one more client
+            if self.connects.len() >= max_clients && !self.clients.contains_key(&connect.client_id) {
+                return Err(message::ConnectError::ServerUnavailable);
+            }
+        }
+
+        Ok(())
+    }
+
+    //MQTT 5 clients can leave it to the server to come up with a client id, after
+    //which it's like any other. Returns the new id, if there is one.
+    fn assign_client_id(&mut self, connect: &mut message::Connect) -> Option<String> {
+        if connect.version != message::ProtocolVersion::V5 || !connect.client_id.is_empty() {
+            return None;
+        }
+
+        loop {
+            self.assigned_ids += 1;
+            let client_id = format!("mqtt_rs-{}", self.assigned_ids);
+            if !self.clients.contains_key(&client_id) && !self.persistent.contains_key(&client_id) {
+                connect.client_id = client_id.clone();
+                return Some(client_id);
+            }
+        }
+    }
+
+    fn connection(&mut self, client: &Rc<RefCell<T>>) -> &mut Connection<T> {
+        self.connections.entry(client_key(client)).or_insert_with(|| Connection {
+            client: client.clone(),
+            state: State::AwaitingConnect,
+            accepted: Instant::now(),
+        })
+    }
+
+    //CONNECT first and only once, anything else is a protocol violation
+    fn is_expected(&mut self, client: &Rc<RefCell<T>>, decoded: &Result<message::Packet, message::DecodeError>) -> bool {
+        let is_connect = match *decoded {
+            Ok(message::Packet::Connect(_)) | Err(message::DecodeError::UnacceptableProtocolVersion) => true,
+            _ => false,
+        };
+
+        match self.connection(client).state {
+            State::AwaitingConnect if is_connect || decoded.is_err() => true,
+            State::AwaitingConnect => {
+                println!("Packet before CONNECT, closing connection");
+                false
+            }
+            State::Connected if !is_connect => true,
+            State::Connected => {
+                println!("Second CONNECT, closing connection");
+                self.disconnect(client.clone(), message::PROTOCOL_ERROR);
+                false
+            }
+            State::Disconnecting => false,
+        }
+    }
+
+    //nothing's been negotiated before CONNECT, which says for itself which version it is
+    fn decode(&self, client: &Rc<RefCell<T>>, bytes: &[u8]) -> Result<message::Packet, message::DecodeError> {
+        let version = self.connects.get(&client_key(client)).map(|connect| connect.version);
+        match (version, self.non_characters) {
+            (None, message::NonCharacters::Allow) => message::decode(bytes),
+            (Some(version), message::NonCharacters::Allow) => message::decode_version(bytes, version),
+            (version, non_characters) =>
+                message::decode_with(bytes, version.unwrap_or(message::ProtocolVersion::V311), non_characters),
+        }
+    }
+
+    //whatever the client negotiated on CONNECT
+    fn version(&self, client: &Rc<RefCell<T>>) -> message::ProtocolVersion {
+        self.connects.get(&client_key(client))
+            .map(|connect| connect.version)
+            .unwrap_or(message::ProtocolVersion::V311)
+    }
+
+    fn session(&mut self, client: &Rc<RefCell<T>>) -> Rc<RefCell<Session<T>>> {
+        let version = self.version(client);
+        self.sessions.entry(client_key(client))
+            .or_insert_with(|| Rc::new(RefCell::new(Session::new(client.clone(), version))))
+            .clone()
+    }
+
+    //only one connection per client id, the newest one wins
+    fn take_over(&mut self, client: &Rc<RefCell<T>>, client_id: &str) {
+        //clients without an id don't have anything to take over
+        if client_id.is_empty() {
+            return;
+        }
+
+        let previous = match self.clients.insert(client_id.to_string(), client.clone()) {
+            Some(ref previous) if client_key(previous) != client_key(client) => previous.clone(),
+            _ => return,
+        };
+
+        self.disconnect(previous, message::SESSION_TAKEN_OVER);
+    }
+
+    //picks up where a previous connection with the same client id left off unless
+    //the client asked for a clean session, returns whether there was one to pick up
+    fn attach_session(&mut self, client: &Rc<RefCell<T>>, connect: &message::Connect) -> bool {
+        let key = client_key(client);
+        //anything done before CONNECT doesn't belong to any session
+        if let Some(session) = self.sessions.remove(&key) {
+            self.broker.unsubscribe_all(session);
+        }
+
+        let previous = self.persistent.remove(&connect.client_id);
+        let (session, session_present) = match previous {
+            Some(ref session) if !connect.clean_session => (session.clone(), true),
+            _ => {
+                if let Some(session) = previous {
+                    self.broker.unsubscribe_all(session);
+                }
+                (Rc::new(RefCell::new(Session::new(client.clone(), connect.version))), false)
+            }
+        };
+
+        let expiry = session_expiry(connect, self.session_expiry);
+        session.borrow_mut().expiry = expiry;
+        session.borrow_mut().receive_maximum = receive_maximum(connect);
+        session.borrow_mut().max_queued = self.max_queued;
+        if expiry > Duration::from_secs(0) {
+            self.persistent.insert(connect.client_id.clone(), session.clone());
+        }
+        self.sessions.insert(key, session);
+        session_present
+    }
+
+    //returns the CONNACK properties needed to tell the client what keep alive is in force
+    fn watch_keep_alive(&mut self, client: &Rc<RefCell<T>>, connect: &message::Connect) -> Vec<message::Property> {
+        let (keep_alive, properties) = match self.server_keep_alive {
+            Some(seconds) if connect.version == message::ProtocolVersion::V5 =>
+                (seconds, vec![message::Property::ServerKeepAlive(seconds)]),
+            _ => (connect.keep_alive, vec![]),
+        };
+
+        //0 means the client doesn't want one
+        if keep_alive == 0 {
+            self.keep_alives.remove(&client_key(client));
+        } else {
+            self.keep_alives.insert(client_key(client), KeepAlive {
+                client: client.clone(),
+                timeout: Duration::from_millis(keep_alive as u64 * 1500),
+                last_heard: Instant::now(),
+            });
+        }
+
+        properties
+    }
+
+    fn keep_alive_expired(&mut self, client: Rc<RefCell<T>>) {
+        self.disconnect(client, message::KEEP_ALIVE_TIMEOUT);
+    }
+
+    //MQTT 5 clients get told why before the server hangs up on them
+    fn disconnect(&mut self, client: Rc<RefCell<T>>, reason_code: u8) {
+        if self.version(&client) == message::ProtocolVersion::V5 {
+            send(&client, message::ProtocolVersion::V5, message::Packet::Disconnect(message::Disconnect {
+                reason_code: reason_code,
+                ..message::Disconnect::new()
+            }));
+        }
+        self.hang_up(client);
+    }
+
+    //as far as the server is concerned the client is gone, whatever the socket
+    //does until the event loop notices
+    fn hang_up(&mut self, client: Rc<RefCell<T>>) {
+        client.borrow_mut().close();
+        self.connection(&client).state = State::Disconnecting;
+        self.connection_lost(client);
+    }
+
+    //a will still waiting on its delay is only published if the session didn't survive
+    fn reconnected(&mut self, client_id: &str, session_present: bool) {
+        let (wills, pending): (Vec<PendingWill>, Vec<PendingWill>) =
+            self.wills.drain(..).partition(|w| w.client_id == client_id);
+        self.wills = pending;
+        if !session_present {
+            for will in wills {
+                self.broker.publish(&will.publish);
+            }
+        }
+    }
+
+    //MQTT 5 wills can be delayed, but not past the end of the session
+    fn publish_will(&mut self, connect: &message::Connect, now: Instant) {
+        let will = match connect.will {
+            Some(ref will) => will,
+            None => return,
+        };
+
+        let mut delay = 0;
+        let mut properties = vec![];
+        for property in &will.properties {
+            match *property {
+                message::Property::WillDelayInterval(seconds) => delay = seconds,
+                _ => properties.push(property.clone()),
+            }
+        }
+
+        let publish = message::Publish {
+            qos: will.qos,
+            retain: will.retain,
+            properties: properties,
+            ..message::Publish::new(&will.topic, &will.message)
+        };
+
+        let delay = Duration::from_secs(delay as u64).min(session_expiry(connect, self.session_expiry));
+        if delay == Duration::from_secs(0) {
+            self.broker.publish(&publish);
+        } else {
+            self.wills.push(PendingWill { client_id: connect.client_id.clone(), publish: publish, due: now + delay });
+        }
+    }
+
+    //to be called every so often to resend messages that haven't been acknowledged,
+    //drop clients that went quiet, publish delayed wills and to get rid of persistent
+    //sessions whose clients never came back
+    pub fn tick(&mut self, now: Instant) {
+        for session in self.sessions.values() {
+            session.borrow_mut().retransmit(now);
+        }
+
+        let quiet: Vec<Rc<RefCell<T>>> = self.keep_alives.values()
+            .filter(|k| now.duration_since(k.last_heard) >= k.timeout)
+            .map(|k| k.client.clone())
+            .collect();
+        for client in quiet {
+            self.keep_alive_expired(client);
+        }
+
+        let timeout = self.connect_timeout;
+        let silent: Vec<Rc<RefCell<T>>> = self.connections.values()
+            .filter(|c| c.state == State::AwaitingConnect && now.duration_since(c.accepted) >= timeout)
+            .map(|c| c.client.clone())
+            .collect();
+        for client in silent {
+            println!("No CONNECT in time, closing connection");
+            self.hang_up(client);
+        }
+
+        let (due, pending): (Vec<PendingWill>, Vec<PendingWill>) = self.wills.drain(..).partition(|w| w.due <= now);
+        self.wills = pending;
+        for will in due {
+            self.broker.publish(&will.publish);
+        }
+
+        let expired: Vec<String> = self.persistent.iter()
+            .filter(|&(_, session)| session.borrow().is_expired(now))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in expired {
+            if let Some(session) = self.persistent.remove(&client_id) {
+                self.broker.unsubscribe_all(session);
+            }
         }
     }
 
-    pub fn unsubscribe_all(&mut self, client: Rc<RefCell<T>>) {
-        self.broker.unsubscribe_all(client);
+    //to be called when the socket's gone
+    pub fn client_disconnected(&mut self, client: Rc<RefCell<T>>) {
+        self.connection_lost(client.clone());
+        self.connections.remove(&client_key(&client));
+    }
+
+    //whether the client said goodbye or not, it's gone: anything but a clean
+    //DISCONNECT gets its will published
+    fn connection_lost(&mut self, client: Rc<RefCell<T>>) {
+        self.keep_alives.remove(&client_key(&client));
+        let connect = self.connects.remove(&client_key(&client));
+        if let Some(ref connect) = connect {
+            //unless it was taken over by a newer connection already
+            let is_registered = self.clients.get(&connect.client_id)
+                .map(|c| client_key(c) == client_key(&client))
+                .unwrap_or(false);
+            if is_registered {
+                self.clients.remove(&connect.client_id);
+            }
+        }
+        if let Some(session) = self.sessions.remove(&client_key(&client)) {
+            //persistent sessions keep their subscriptions for when the client comes back
+            if session.borrow().is_persistent() {
+                session.borrow_mut().detach(Instant::now());
+            } else {
+                self.broker.unsubscribe_all(session);
+            }
+        }
+
+        if let Some(ref connect) = connect {
+            self.publish_will(connect, Instant::now());
+        }
     }
 }
 
+//enough for anything sensible, a client sending more than this is dropped
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 1024 * 512;
+
+//bytes come off the socket in whatever chunks they please; this holds on
+//to them until there's at least one whole packet to hand to the server
 pub struct Stream {
-    buffer: Vec<u8>,
-    bytes_start: usize, //the start of the next byte window
+    bytes: Vec<u8>,
+    start: usize, //where the next packet starts in bytes
+    max_packet_size: usize,
 }
 
 impl Stream {
-    pub fn new() -> Self {
-        Stream { buffer: vec![0; 1024 * 512], bytes_start: 0 }
+    pub fn new(max_packet_size: usize) -> Self {
+        Stream { bytes: vec![], start: 0, max_packet_size: max_packet_size }
     }
 
-    pub fn buffer(&mut self) -> &mut [u8] {
-        &mut self.buffer[self.bytes_start .. ]
+    pub fn push(&mut self, bytes: &[u8]) {
+        //get rid of packets already handled before growing
+        self.bytes.drain(.. self.start);
+        self.start = 0;
+        self.bytes.extend_from_slice(bytes);
     }
 
-    pub fn total_buffer_len(&self) -> usize {
-        self.buffer.len()
+    //the next complete packet, if there's one
+    pub fn next_packet(&mut self) -> Result<Option<&[u8]>, message::DecodeError> {
+        let total_len = {
+            let bytes = &self.bytes[self.start ..];
+            match message::total_length(bytes) {
+                Ok(total_len) => total_len,
+                Err(message::DecodeError::Truncated) => return Ok(None), //header not all here yet
+                Err(error) => return Err(error),
+            }
+        };
+
+        //no point waiting for the rest of it
+        if total_len > self.max_packet_size {
+            return Err(message::DecodeError::PacketTooLarge);
+        }
+
+        if total_len > self.bytes.len() - self.start {
+            return Ok(None);
+        }
+
+        let packet = &self.bytes[self.start .. self.start + total_len];
+        self.start += total_len;
+        Ok(Some(packet))
     }
 
-    pub fn handle_messages<T: broker::Subscriber>(&mut self, bytes_read:
-                                                  usize, server: &mut Server<T>,
-                                                  client: Rc<RefCell<T>>) -> bool {
-        let vec : Vec<u8>;
-        let mut res = true;
-        {
-            let mut slice = &self.buffer[0 .. self.bytes_start + bytes_read];
-            const HEADER_LEN: usize = 2;
-            let mut total_len = HEADER_LEN;
-            while slice.len() >= total_len {
+    //how many bytes are waiting for the rest of their packet
+    pub fn pending_len(&self) -> usize {
+        self.bytes.len() - self.start
+    }
 
-                total_len = message::total_length(slice);
-                if total_len > slice.len() {
-                    self.bytes_start += bytes_read;
-                    return true;
+    pub fn handle_messages<T: Client>(&mut self, bytes: &[u8], server: &mut Server<T>,
+                                      client: Rc<RefCell<T>>) -> bool {
+        self.push(bytes);
+        let res = loop {
+            match self.next_packet() {
+                Ok(Some(packet)) => {
+                    if !server.new_message(client.clone(), packet) {
+                        break false;
+                    }
                 }
-                let msg = &slice[0 .. total_len];
-                slice = &slice[total_len..];
-                self.bytes_start += total_len;
-                res = res && server.new_message(client.clone(), msg);
-
-                if slice.len() >= 2 {
-                    total_len = message::total_length(slice);
+                Ok(None) => break true,
+                Err(error) => {
+                    println!("Could not read packet ({:?}), closing connection", error);
+                    break false;
                 }
             }
-            vec = slice.to_vec();
-        }
+        };
 
-        //shift everything to the beginning of the buffer
-        for i in 0 .. vec.len() {
-            self.buffer[i] = vec[i];
+        //idle connections shouldn't hang on to the memory of the last big packet
+        if self.pending_len() == 0 {
+            self.bytes = vec![];
+            self.start = 0;
         }
 
-        self.bytes_start = vec.len();
         res
     }
 }
@@ -121,301 +967,1682 @@ impl Stream {
 struct TestClient {
     msgs: Vec<Vec<u8>>,
     payloads: Vec<Vec<u8>>,
+    closed: bool,
+}
+
+#[cfg(test)]
+impl TestClient {
+    fn new() -> Self {
+        TestClient { msgs: vec![], payloads: vec![], closed: false }
+    }
+
+    fn last_msg(&self) -> &[u8] {
+        self.msgs.last().expect("TestClient has no last message")
+    }
+}
+
+#[cfg(test)]
+impl Client for TestClient {
+    fn send(&mut self, bytes: &[u8]) {
+        self.msgs.push(bytes.to_vec());
+        if let Ok(message::Packet::Publish(publish)) = message::decode(bytes) {
+            self.payloads.push(publish.payload);
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+    }
+}
+
+
+//a clean session with a keep alive of 10 seconds, tests change whatever else they need
+#[cfg(test)]
+fn connect_packet(version: message::ProtocolVersion, client_id: &str) -> message::Connect {
+    message::Connect {
+        version: version,
+        clean_session: true,
+        keep_alive: 10,
+        client_id: client_id.to_string(),
+        will: None,
+        username: None,
+        password: None,
+        properties: vec![],
+    }
+}
+
+#[cfg(test)]
+fn will_packet() -> message::Will {
+    message::Will {
+        topic: "will".to_string(),
+        message: b"wmsg".to_vec(),
+        qos: 0,
+        retain: false,
+        properties: vec![],
+    }
+}
+
+#[cfg(test)]
+fn connect_bytes(connect: &message::Connect) -> Vec<u8> {
+    let mut bytes = vec![];
+    message::Packet::Connect(connect.clone()).encode_version(connect.version, &mut bytes);
+    bytes
+}
+
+//a client that's sent CONNECT already, with the CONNACK out of the way
+#[cfg(test)]
+fn connected_client(server: &mut Server<TestClient>) -> Rc<RefCell<TestClient>> {
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let client_id = format!("client{}", client_key(&client));
+    let connect_bytes = connect_bytes(&message::Connect {
+        keep_alive: 0,
+        ..connect_packet(message::ProtocolVersion::V311, &client_id)
+    });
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &CONNACK_OK);
+    client.borrow_mut().msgs.clear();
+    client
+}
+
+#[test]
+fn test_connect() {
+    let connect_bytes = &[
+        0x10u8, 0x2a, // fixed header
+        0x00, 0x06, 'M' as u8, 'Q' as u8, 'I' as u8, 's' as u8, 'd' as u8, 'p' as u8,
+        0x03, // protocol version
+        0xcc, // connection flags 1100111x user, pw, !wr, w(01), w, !c, x
+        0x00, 0x0a, // keepalive of 100
+        0x00, 0x03, 'c' as u8, 'i' as u8, 'd' as u8, // client ID
+        0x00, 0x04, 'w' as u8, 'i' as u8, 'l' as u8, 'l' as u8, // will topic
+        0x00, 0x04, 'w' as u8, 'm' as u8, 's' as u8, 'g' as u8, // will msg
+        0x00, 0x07, 'g' as u8, 'l' as u8, 'i' as u8, 'f' as u8, 't' as u8, 'e' as u8, 'l' as u8, // username
+        0x00, 0x02, 'p' as u8, 'w' as u8, // password
+        ][0..];
+
+    let mut server = Server::<TestClient>::new(false);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+
+    server.new_message(client.clone(), connect_bytes);
+    assert_eq!(client.borrow().msgs.len(), 1);
+    assert_eq!(client.borrow().last_msg(), &CONNACK_OK);
+
+    {
+        let connect = &server.connects[&client_key(&client)];
+        assert_eq!(connect.client_id, "cid");
+        assert_eq!(connect.keep_alive, 10);
+        assert_eq!(connect.username, Some("gliftel".to_string()));
+    }
+
+    server.client_disconnected(client.clone());
+    assert_eq!(server.connects.len(), 0);
+}
+
+
+#[test]
+fn test_ping() {
+    let ping_bytes =  &[0xc0u8, 0][0..];
+
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    server.new_message(client.clone(), ping_bytes);
+
+    assert_eq!(client.borrow().last_msg(), &PING_RESP);
+}
+
+
+#[test]
+fn test_pings_all_at_once() {
+    let ping_bytes = &[0xc0u8, 0, 0xc0, 0, 0xc0, 0, 0xc0, 0][0..];
+
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    stream.handle_messages(ping_bytes, &mut server, client.clone());
+
+    let client = client.clone();
+    assert_eq!(client.borrow().msgs.len(), 4);
+    for msg in &client.borrow().msgs {
+        assert_eq!(msg, &PING_RESP);
+    }
+}
+
+#[test]
+fn test_pings_multiple_time_unbroken() {
+    let ping_bytes = &[0xc0u8, 0, 0xc0, 0][0..];
+
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    stream.handle_messages(ping_bytes, &mut server, client.clone());
+    assert_eq!(client.borrow().msgs.len(), 2);
+
+    stream.handle_messages(ping_bytes, &mut server, client.clone());
+    assert_eq!(client.borrow().msgs.len(), 4);
+
+    for msg in &client.borrow().msgs {
+        assert_eq!(msg, &PING_RESP);
+    }
+}
+
+
+#[test]
+fn test_pings_broken() {
+    let ping_fst = &[0xc0u8][0..];
+    let ping_snd = &[0u8][0..];
+
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    stream.handle_messages(ping_fst, &mut server, client.clone());
+    assert_eq!(client.borrow().msgs.len(), 0);
+
+    stream.handle_messages(ping_snd, &mut server, client.clone());
+    assert_eq!(client.borrow().msgs.len(), 1);
+
+    stream.handle_messages(ping_fst, &mut server, client.clone());
+    assert_eq!(client.borrow().msgs.len(), 1);
+
+    stream.handle_messages(ping_snd, &mut server, client.clone());
+    assert_eq!(client.borrow().msgs.len(), 2);
+
+    for msg in &client.borrow().msgs {
+        assert_eq!(msg, &PING_RESP);
+    }
+}
+
+#[cfg(test)]
+fn string_to_bytes(s: &str) -> Vec<u8> {
+    let mut vec: Vec<u8> = vec![];
+    for c in s.chars() {
+        vec.push(c as u8);
+    }
+    vec
+}
+
+#[cfg(test)]
+fn subscribe_bytes(topic: &str, msg_id: u16) -> Vec<u8> {
+    let mut fixed_header = [0x82u8, 5 + topic.len() as u8].to_vec();
+    let mut msg_part = vec![(msg_id >> 8) as u8, msg_id as u8];
+    let mut topic_header = vec![0, topic.len() as u8];
+    let mut string_bytes = string_to_bytes(topic);
+    let mut bytes = vec![];
+    bytes.append(&mut fixed_header);
+    bytes.append(&mut msg_part);
+    bytes.append(&mut topic_header);
+    bytes.append(&mut string_bytes);
+    bytes.push(0u8); //qos
+    bytes
+}
+
+#[test]
+fn test_suback_bytes() {
+    let subscribe_bytes = &subscribe_bytes("topic", 42)[..];
+    let qos: u8 = 0;
+    let suback_bytes = &[0x90u8, 3, 0, 42, qos][..];
+
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    stream.handle_messages(&subscribe_bytes, &mut server, client.clone());
+    assert_eq!(client.borrow().last_msg(), suback_bytes);
+}
+
+#[test]
+fn test_suback_16_bit_msg_id() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+
+    assert_eq!(server.new_message(client.clone(), &subscribe_bytes("topic", 0x1234)), true);
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 3, 0x12, 0x34, 0][..]);
+
+    assert_eq!(server.new_message(client.clone(), &subscribe_bytes("topic", 300)), true);
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 3, 1, 44, 0][..]);
+}
+
+#[test]
+fn test_zero_msg_id_closes_connection() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+
+    assert_eq!(server.new_message(client.clone(), &subscribe_bytes("topic", 0)), true);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().msgs.len(), 0);
+}
+
+
+#[test]
+fn test_subscribe() {
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    let pub_bytes = vec![
+        0x3c, 0x0d, //fixed header
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,//topic name
+        0x00, 0x21, //message ID
+        'b' as u8, 'o' as u8, 'r' as u8, 'g' as u8, //payload
+        //--
+        0x62, 2, 0, 0x21, //pubrel
+        ];
+    assert_eq!(stream.handle_messages(&pub_bytes, &mut server, client.clone()), true);
+    assert_eq!(client.borrow().payloads.len(), 0);
+
+    let sub_bytes = vec![
+        0x82, 0x13, //fixed header
+        0x00, 0x21, //message ID
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,
+        0x01, //qos
+        0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,
+        0x02, //qos
+        ];
+    assert_eq!(stream.handle_messages(&sub_bytes, &mut server, client.clone()), true);
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 4, 0, 0x21, 1, 2][..]);
+
+    let pub_bytes = vec![
+        0x3c, 0x0d, //fixed header
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,//topic name
+        0x00, 0x21, //message ID
+        'b' as u8, 'o' as u8, 'r' as u8, 'g' as u8, //payload
+        //--
+        0x62, 2, 0, 0x21, //pubrel
+        ];
+    assert_eq!(stream.handle_messages(&pub_bytes, &mut server, client.clone()), true);
+
+    let pub_bytes = vec![
+        0x3c, 0x0d, //fixed header
+        0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,//topic name
+        0x00, 0x21, //message ID
+        'f' as u8, 'o' as u8, 'o' as u8,//payload
+        //--
+        0x62, 2, 0, 0x21, //pubrel
+        ];
+    assert_eq!(stream.handle_messages(&pub_bytes, &mut server, client.clone()), true);
+
+    let pub_bytes = vec![
+        0x3c, 0x0c, //fixed header
+        0x00, 0x05, 't' as u8, 'h' as u8, 'i' as u8, 'r' as u8, 'd' as u8,//topic name
+        0x00, 0x21, //message ID
+        'f' as u8, 'o' as u8, 'o' as u8,//payload
+        //--
+        0xe0, 0, //disconnect
+        ];
+    //false since last msg is disconnect
+    assert_eq!(stream.handle_messages(&pub_bytes, &mut server, client.clone()), false);
+
+    assert_eq!(client.borrow().payloads, vec![b"borg".to_vec(), b"foo".to_vec()]);
+}
+
+#[test]
+fn test_bug1() {
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    //printed from when the bug occurred
+    let first = vec![
+        48, 30, 0, 12, 108, 111, 97, 100, 116, 101, 115, 116, 47, 49, 54, 54
+            ];
+    stream.handle_messages(&first, &mut server, client.clone());
+    assert_eq!(client.borrow().payloads.len(), 0);
+}
+
+
+
+#[test]
+fn test_publish_in_two_msgs() {
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    let sub_bytes = vec![
+        0x82, 0x13, //fixed header
+        0x00, 0x21, //message ID
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,
+        0x01, //qos
+        0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,
+        0x02, //qos
+        ];
+    assert_eq!(stream.handle_messages(&sub_bytes, &mut server, client.clone()), true);
+
+    //1st part of message
+    let pub_bytes = vec![
+        0x3c, 0x0d, //fixed header
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,//topic name
+        ];
+    assert_eq!(stream.handle_messages(&pub_bytes, &mut server, client.clone()), true);
+    assert_eq!(client.borrow().payloads.len(), 0);
+
+    //2nd part of message
+    let pub_bytes = vec![
+        0x00, 0x21, //message ID
+        'b' as u8, 'o' as u8, 'r' as u8, 'g' as u8, //payload
+        ];
+    assert_eq!(stream.handle_messages(&pub_bytes, &mut server, client.clone()), true);
+    assert_eq!(client.borrow().payloads.len(), 1);
+}
+
+#[test]
+fn test_malformed_message_closes_connection() {
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+    let client = client.clone();
+
+    let bad_bytes = vec![
+        0x30, 0x06, //fixed header
+        0x00, 0x02, 0xc3, 0x28, //topic name that isn't UTF-8
+        1, 2, //payload
+        ];
+    assert_eq!(stream.handle_messages(&bad_bytes, &mut server, client.clone()), true);
+    assert_eq!(client.borrow().closed, true);
+
+    let mut other_stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let other = connected_client(&mut server);
+    assert_eq!(other_stream.handle_messages(&[0xc0u8, 0], &mut server, other.clone()), true);
+    assert_eq!(other.borrow().last_msg(), &PING_RESP);
+}
+
+#[test]
+fn test_unknown_message_type_closes_connection() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+
+    assert_eq!(server.new_message(client.clone(), &[0xf0u8, 0]), true);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().msgs.len(), 0);
+}
+
+#[test]
+fn test_publish_subscription_identifier_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    let subscriber = subscribed_client(&mut server, "topic", 0);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+
+    let pub_bytes = vec![0x30, 11, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8,
+                         2, 0x0b, 1, // properties, a subscription identifier of 1
+                         42];
+    assert_eq!(server.new_message(client.clone(), &pub_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0xe0u8, 1, message::PROTOCOL_ERROR][..]);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
+}
+
+#[test]
+fn test_malformed_packet_disconnect_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+
+    assert_eq!(server.new_message(client.clone(), &[0x00u8, 0]), true);
+    assert_eq!(client.borrow().last_msg(), &[0xe0u8, 1, message::MALFORMED_PACKET][..]);
+    assert_eq!(client.borrow().closed, true);
+}
+
+#[test]
+fn test_server_packets_from_client() {
+    let mut server = Server::<TestClient>::new(false);
+    let subscriber = subscribed_client(&mut server, "will", 0);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let will_bytes = connect_bytes(&message::Connect {
+        will: Some(will_packet()),
+        ..connect_packet(message::ProtocolVersion::V5, "meter")
+    });
+    assert_eq!(server.new_message(client.clone(), &will_bytes), true);
+    assert_eq!(server.new_message(client.clone(), &[0x20, 3, 0, 0, 0]), true); //CONNACK
+    assert_eq!(client.borrow().last_msg(), &[0xe0u8, 1, message::PROTOCOL_ERROR][..]);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(subscriber.borrow().payloads, vec![b"wmsg".to_vec()]);
+
+    //no DISCONNECT before MQTT 5
+    let client = connected_client(&mut server);
+    assert_eq!(server.new_message(client.clone(), &PING_RESP), true);
+    assert_eq!(client.borrow().msgs.len(), 0);
+    assert_eq!(client.borrow().closed, true);
+}
+
+#[test]
+fn test_publish_with_long_remaining_length() {
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+
+    assert_eq!(stream.handle_messages(&subscribe_bytes("topic", 1), &mut server, client.clone()), true);
+
+    let mut pub_bytes = vec![];
+    message::Packet::Publish(message::Publish::new("topic", &vec![42; 300])).encode(&mut pub_bytes);
+
+    //split in the middle of the remaining length
+    assert_eq!(stream.handle_messages(&pub_bytes[0 .. 2], &mut server, client.clone()), true);
+    assert_eq!(client.borrow().payloads.len(), 0);
+
+    assert_eq!(stream.handle_messages(&pub_bytes[2 ..], &mut server, client.clone()), true);
+    assert_eq!(client.borrow().payloads, vec![vec![42; 300]]);
+}
+
+#[test]
+fn test_connect_versions() {
+    let mut server = Server::<TestClient>::new(false);
+
+    let client31 = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client31.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V31, "cid"))), true);
+    assert_eq!(client31.borrow().last_msg(), &CONNACK_OK);
+    assert_eq!(server.version(&client31), message::ProtocolVersion::V31);
+
+    let client311 = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client311.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "cid"))), true);
+    assert_eq!(client311.borrow().last_msg(), &CONNACK_OK);
+    assert_eq!(server.version(&client311), message::ProtocolVersion::V311);
+
+    let client5 = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client5.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+    assert_eq!(client5.borrow().last_msg(), &[0x20u8, 3, 0, 0, 0][..]);
+    assert_eq!(server.version(&client5), message::ProtocolVersion::V5);
+
+    //MQTT 5 subscribe has properties after the msg id
+    let sub_bytes = vec![
+        0x82, 0x0b, //fixed header
+        0x00, 0x07, //message ID
+        0x00, //properties
+        0x00, 0x05, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8,
+        0x00, //subscription options
+        ];
+    assert_eq!(server.new_message(client5.clone(), &sub_bytes), true);
+    assert_eq!(client5.borrow().last_msg(), &[0x90u8, 4, 0, 7, 0, 0][..]);
+}
+
+#[test]
+fn test_connect_bad_version() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+
+    let connect_bytes = vec![0x10u8, 15, 0, 4, 'M' as u8, 'Q' as u8, 'T' as u8, 'T' as u8, 6, 0x02, 0, 0x0a,
+                             0, 3, 'c' as u8, 'i' as u8, 'd' as u8];
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 2, 0, 1][..]);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(server.connects.len(), 0);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let connect_bytes = vec![0x10u8, 17, 0, 6, 'M' as u8, 'Q' as u8, 'I' as u8, 's' as u8, 'd' as u8, 'p' as u8, 4, 0x02, 0, 0x0a,
+                             0, 3, 'c' as u8, 'i' as u8, 'd' as u8];
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 2, 0, 1][..]);
+    assert_eq!(client.borrow().closed, true);
+}
+
+#[test]
+fn test_publish_acks() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+
+    //QoS 1
+    assert_eq!(server.new_message(client.clone(), &[0x32, 5, 0, 1, 't' as u8, 0x12, 0x34]), true);
+    assert_eq!(client.borrow().last_msg(), &[0x40u8, 2, 0x12, 0x34][..]);
+
+    //QoS 2
+    assert_eq!(server.new_message(client.clone(), &[0x34, 5, 0, 1, 't' as u8, 0, 7]), true);
+    assert_eq!(client.borrow().last_msg(), &[0x50u8, 2, 0, 7][..]);
+    assert_eq!(server.new_message(client.clone(), &[0x62, 2, 0, 7]), true);
+    assert_eq!(client.borrow().last_msg(), &[0x70u8, 2, 0, 7][..]);
+
+    //QoS 3 doesn't exist
+    assert_eq!(server.new_message(client.clone(), &[0x36, 5, 0, 1, 't' as u8, 0, 8]), true);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().msgs.len(), 3);
+}
+
+#[test]
+fn test_publish_reencoded_for_subscribers() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber = connected_client(&mut server);
+
+    assert_eq!(server.new_message(subscriber.clone(), &subscribe_bytes("topic", 1)), true);
+
+    //DUP and RETAIN set, QoS 1
+    let pub_bytes = vec![
+        0x3b, 0x0b, //fixed header
+        0x00, 0x05, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, //topic name
+        0x00, 0x21, //message ID
+        1, 2, //payload
+        ];
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+    assert_eq!(subscriber.borrow().last_msg(),
+               &[0x30u8, 9, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 1, 2][..]);
+}
+
+#[test]
+fn test_stream_one_byte_at_a_time() {
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    let client = connected_client(&mut server);
+
+    let bytes = subscribe_bytes("topic", 3);
+    for byte in &bytes {
+        assert_eq!(client.borrow().msgs.len(), 0);
+        assert_eq!(stream.handle_messages(&[*byte], &mut server, client.clone()), true);
+    }
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 3, 0, 3, 0][..]);
+    assert_eq!(stream.pending_len(), 0);
+}
+
+#[test]
+fn test_stream_next_packet() {
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    stream.push(&[0xc0, 0, 0xd0]);
+    assert_eq!(stream.next_packet(), Ok(Some(&[0xc0u8, 0][..])));
+    assert_eq!(stream.next_packet(), Ok(None));
+    assert_eq!(stream.pending_len(), 1);
+
+    stream.push(&[0, 0xe0, 0]);
+    assert_eq!(stream.next_packet(), Ok(Some(&[0xd0u8, 0][..])));
+    assert_eq!(stream.next_packet(), Ok(Some(&[0xe0u8, 0][..])));
+    assert_eq!(stream.next_packet(), Ok(None));
+    assert_eq!(stream.pending_len(), 0);
+}
+
+#[test]
+fn test_stream_max_packet_size() {
+    let mut stream = Stream::new(10);
+    stream.push(&[0x30, 8]); //exactly 10 bytes once it's all here
+    assert_eq!(stream.next_packet(), Ok(None));
+
+    let mut stream = Stream::new(10);
+    stream.push(&[0x30, 9]); //no need to wait for the rest
+    assert_eq!(stream.next_packet(), Err(message::DecodeError::PacketTooLarge));
+
+    let mut server = Server::<TestClient>::new(false);
+    let mut stream = Stream::new(100);
+    let client = connected_client(&mut server);
+    let mut pub_bytes = vec![];
+    message::Packet::Publish(message::Publish::new("topic", &vec![42; 300])).encode(&mut pub_bytes);
+    assert_eq!(stream.handle_messages(&pub_bytes[0 .. 3], &mut server, client.clone()), false);
+}
+
+#[test]
+fn test_stream_malformed_remaining_length() {
+    let mut stream = Stream::new(DEFAULT_MAX_PACKET_SIZE);
+    stream.push(&[0x30, 0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(stream.next_packet(), Err(message::DecodeError::MalformedRemainingLength));
+}
+
+#[test]
+fn test_subscribe_invalid_topic_filter() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+
+    let sub_bytes = vec![
+        0x82, 0x13, //fixed header
+        0x00, 0x21, //message ID
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, '#' as u8,
+        0x00, //qos
+        0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,
+        0x00, //qos
+        ];
+    assert_eq!(server.new_message(client.clone(), &sub_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 4, 0, 0x21, 0x80, 0][..]);
+}
+
+#[test]
+fn test_subscribe_invalid_topic_filter_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+
+    let sub_bytes = vec![
+        0x82, 0x0e, //fixed header
+        0x00, 0x21, //message ID
+        0x00, //properties
+        0x00, 0x03, 'a' as u8, '/' as u8, '+' as u8,
+        0x01, //qos
+        0x00, 0x02, 'b' as u8, '+' as u8,
+        0x00, //qos
+        ];
+    assert_eq!(server.new_message(client.clone(), &sub_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 5, 0, 0x21, 0, 1, 0x8f][..]);
+}
+
+#[test]
+fn test_subscribe_no_topics_closes_connection() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+
+    assert_eq!(server.new_message(client.clone(), &[0x82, 2, 0, 1]), true);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().msgs.len(), 0);
+}
+
+#[test]
+fn test_publish_wildcard_topic_closes_connection() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+
+    assert_eq!(server.new_message(client.clone(), &subscribe_bytes("topic/+", 1)), true);
+    assert_eq!(server.new_message(client.clone(), &[0x30, 9, 0, 7, 't' as u8, 'o' as u8, 'p' as u8,
+                                                    'i' as u8, 'c' as u8, '/' as u8, '+' as u8]), true);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().payloads.len(), 0);
+}
+
+#[test]
+fn test_bad_strings_close_connection() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+
+    let connect_bytes = connect_bytes(&connect_packet(message::ProtocolVersion::V311, "c\u{0}d"));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().msgs.len(), 0);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let sub_bytes = vec![0x82, 8, 0, 1, 0, 3, 0xed, 0xa0, 0x80, 0]; //UTF-16 surrogate
+    assert_eq!(server.new_message(client.clone(), &sub_bytes), true);
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().msgs.len(), 0);
+}
+
+#[test]
+fn test_reject_non_characters() {
+    let pub_bytes = vec![0x30, 5, 0, 3, 0xef, 0xbf, 0xbf]; //U+FFFF
+
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+    assert_eq!(server.new_message(client.clone(), &pub_bytes), true);
+
+    server.reject_non_characters();
+    assert_eq!(server.new_message(client.clone(), &pub_bytes), true);
+    assert_eq!(client.borrow().closed, true);
+}
+
+#[test]
+fn test_retained_publish() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber = connected_client(&mut server);
+
+    let pub_bytes = vec![
+        0x31, 0x09, //fixed header, retained
+        0x00, 0x05, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, //topic name
+        1, 2, //payload
+        ];
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+
+    assert_eq!(server.new_message(subscriber.clone(), &subscribe_bytes("topic", 1)), true);
+    assert_eq!(subscriber.borrow().msgs.len(), 2);
+    assert_eq!(subscriber.borrow().msgs[0], pub_bytes);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1, 2]]);
+}
+
+#[test]
+fn test_suback_granted_qos() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = connected_client(&mut server);
+
+    let sub_bytes = vec![
+        0x82, 0x13, //fixed header
+        0x00, 0x21, //message ID
+        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,
+        0x01, //qos
+        0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,
+        0x02, //qos
+        ];
+    assert_eq!(server.new_message(client.clone(), &sub_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 4, 0, 0x21, 1, 2][..]);
+}
+
+#[test]
+fn test_publish_qos_per_subscriber() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber0 = connected_client(&mut server);
+    let subscriber1 = connected_client(&mut server);
+
+    assert_eq!(server.new_message(subscriber0.clone(), &subscribe_bytes("topic", 1)), true);
+    assert_eq!(server.new_message(subscriber1.clone(),
+                                  &[0x82, 10, 0, 1, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 1]),
+               true);
+
+    //QoS 2 with message ID 0x21
+    let pub_bytes = vec![
+        0x34, 0x0b, //fixed header
+        0x00, 0x05, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, //topic name
+        0x00, 0x21, //message ID
+        1, 2, //payload
+        ];
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+    assert_eq!(server.new_message(publisher.clone(), &[0x62, 2, 0, 0x21]), true);
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+
+    assert_eq!(subscriber0.borrow().last_msg(),
+               &[0x30u8, 9, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 1, 2][..]);
+
+    //each subscriber connection has its own message IDs
+    let msgs = &subscriber1.borrow().msgs;
+    assert_eq!(msgs.len(), 3);
+    assert_eq!(msgs[1], vec![0x32u8, 11, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 1, 1, 2]);
+    assert_eq!(msgs[2], vec![0x32u8, 11, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 2, 1, 2]);
+}
+
+#[cfg(test)]
+fn subscribed_client(server: &mut Server<TestClient>, topic: &str, qos: u8) -> Rc<RefCell<TestClient>> {
+    let client = connected_client(server);
+    assert_eq!(server.new_message(client.clone(), &subscribe_bytes_qos(topic, qos)), true);
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 3, 0, 1, qos][..]);
+    client
+}
+
+#[test]
+fn test_qos1_retransmit() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber = subscribed_client(&mut server, "topic", 1);
+
+    let pub_bytes = vec![0x32, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 7, 1];
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+    assert_eq!(publisher.borrow().last_msg(), &[0x40u8, 2, 0, 7][..]);
+    assert_eq!(subscriber.borrow().msgs.len(), 2);
+    assert_eq!(subscriber.borrow().last_msg(),
+               &[0x32u8, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 1, 1][..]);
+
+    //not yet
+    server.tick(Instant::now());
+    assert_eq!(subscriber.borrow().msgs.len(), 2);
+
+    let later = Instant::now() + Duration::from_secs(RETRANSMIT_SECS + 1);
+    server.tick(later);
+    assert_eq!(subscriber.borrow().msgs.len(), 3);
+    assert_eq!(subscriber.borrow().last_msg(),
+               &[0x3au8, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 1, 1][..]);
+
+    //the timeout starts again after resending
+    server.tick(later);
+    assert_eq!(subscriber.borrow().msgs.len(), 3);
+
+    assert_eq!(server.new_message(subscriber.clone(), &[0x40, 2, 0, 1]), true);
+    server.tick(later + Duration::from_secs(RETRANSMIT_SECS + 1));
+    assert_eq!(subscriber.borrow().msgs.len(), 3);
+}
+
+#[test]
+fn test_qos0_not_retransmitted() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber = subscribed_client(&mut server, "topic", 0);
+
+    let pub_bytes = vec![0x32, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 7, 1];
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+    assert_eq!(subscriber.borrow().msgs.len(), 2);
+
+    server.tick(Instant::now() + Duration::from_secs(RETRANSMIT_SECS + 1));
+    assert_eq!(subscriber.borrow().msgs.len(), 2);
+}
+
+#[test]
+fn test_v5_not_retransmitted_on_timeout() {
+    let mut server = Server::<TestClient>::new(false);
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+    //no keep alive to get in the way
+    let connect_bytes = connect_bytes(&message::Connect {
+        keep_alive: 0,
+        ..connect_packet(message::ProtocolVersion::V5, "cid")
+    });
+    assert_eq!(server.new_message(subscriber.clone(), &connect_bytes), true);
+    let sub_bytes = vec![0x82, 0x0b, 0x00, 0x07, 0x00, 0x00, 0x05, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0x01];
+    assert_eq!(server.new_message(subscriber.clone(), &sub_bytes), true);
+
+    let publisher = connected_client(&mut server);
+    let pub_bytes = vec![0x32, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 7, 1];
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+    assert_eq!(subscriber.borrow().msgs.len(), 3);
+
+    server.tick(Instant::now() + Duration::from_secs(RETRANSMIT_SECS + 1));
+    assert_eq!(subscriber.borrow().msgs.len(), 3);
+}
+
+#[test]
+fn test_packet_ids_skip_inflight() {
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let mut session = Session::new(client.clone(), message::ProtocolVersion::V311);
+
+    let publish = message::Publish { qos: 1, ..message::Publish::new("topic", &[]) };
+    broker::Subscriber::new_message(&mut session, &publish);
+    broker::Subscriber::new_message(&mut session, &publish);
+    assert_eq!(session.inflight.iter().map(|i| i.publish.packet_id).collect::<Vec<_>>(),
+               vec![Some(1), Some(2)]);
+
+    session.last_packet_id = 0xffff;
+    assert_eq!(session.next_packet_id(), Some(3));
+
+    session.acknowledged(1);
+    session.last_packet_id = 0xffff;
+    assert_eq!(session.next_packet_id(), Some(1));
+}
+
+#[test]
+fn test_packet_ids_exhausted() {
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let mut session = Session::new(client.clone(), message::ProtocolVersion::V311);
+
+    //a client that never acknowledges anything
+    let publish = message::Publish { qos: 1, ..message::Publish::new("topic", &[]) };
+    for packet_id in 1 .. 0x10000 {
+        session.inflight.push(Inflight {
+            publish: message::Publish { packet_id: Some(packet_id as u16), ..publish.clone() },
+            released: false,
+            sent: Instant::now(),
+        });
+    }
+    assert_eq!(session.next_packet_id(), None);
+
+    broker::Subscriber::new_message(&mut session, &message::Publish::new("topic", &[1]));
+    broker::Subscriber::new_message(&mut session, &message::Publish { qos: 1, ..message::Publish::new("topic", &[2]) });
+    assert_eq!(session.queued.len(), 1);
+    assert_eq!(client.borrow().payloads, vec![vec![1]]); //QoS 0 isn't held up
+
+    session.acknowledged(42);
+    assert_eq!(session.queued.len(), 0);
+    assert_eq!(session.inflight.len(), 0xffff);
+    assert_eq!(client.borrow().payloads, vec![vec![1], vec![2]]);
+    assert_eq!(session.inflight.last().map(|i| i.publish.packet_id), Some(Some(42)));
+}
+
+#[test]
+fn test_assigned_client_id_v5() {
+    let mut server = Server::<TestClient>::new(false);
+
+    //an empty client id and a session expiry interval of 60 seconds
+    let assign_bytes = connect_bytes(&message::Connect {
+        properties: vec![message::Property::SessionExpiryInterval(60)],
+        ..connect_packet(message::ProtocolVersion::V5, "")
+    });
+
+    let assigned_id = |client: &Rc<RefCell<TestClient>>| {
+        match message::decode_version(client.borrow().last_msg(), message::ProtocolVersion::V5) {
+            Ok(message::Packet::ConnAck(connack)) => {
+                assert_eq!(connack.return_code, message::SUCCESS);
+                connack.properties.iter().filter_map(|p| match *p {
+                    message::Property::AssignedClientIdentifier(ref id) => Some(id.clone()),
+                    _ => None,
+                }).next().expect("No assigned client identifier in CONNACK")
+            }
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+    };
+
+    let client1 = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client1.clone(), &assign_bytes), true);
+    let client_id1 = assigned_id(&client1);
+    assert!(!client_id1.is_empty());
+    assert_eq!(server.connects[&client_key(&client1)].client_id, client_id1);
+    assert!(server.clients.contains_key(&client_id1));
+    assert!(server.persistent.contains_key(&client_id1));
+
+    let client2 = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client2.clone(), &assign_bytes), true);
+    assert!(assigned_id(&client2) != client_id1);
+
+    //with the id it was given the client can get its session back
+    server.client_disconnected(client1);
+    let client3 = Rc::new(RefCell::new(TestClient::new()));
+    let resume_bytes = connect_bytes(&message::Connect {
+        clean_session: false,
+        ..connect_packet(message::ProtocolVersion::V5, &client_id1)
+    });
+    assert_eq!(server.new_message(client3.clone(), &resume_bytes), true);
+    assert_eq!(client3.borrow().last_msg(), &[0x20u8, 3, 1, 0, 0][..]);
+}
+
+#[test]
+fn test_receive_maximum_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+
+    let connect_bytes = connect_bytes(&message::Connect {
+        properties: vec![message::Property::ReceiveMaximum(1)],
+        ..connect_packet(message::ProtocolVersion::V5, "cid")
+    });
+    assert_eq!(server.new_message(subscriber.clone(), &connect_bytes), true);
+    let sub_bytes = vec![0x82, 11, 0, 1, 0, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 1];
+    assert_eq!(server.new_message(subscriber.clone(), &sub_bytes), true);
+    subscriber.borrow_mut().msgs.clear();
+
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 1, &[1])), true);
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 1, &[2])), true);
+    assert_eq!(subscriber.borrow().msgs.len(), 1);
+
+    assert_eq!(server.new_message(subscriber.clone(), &[0x40, 2, 0, 1]), true); // PUBACK
+    assert_eq!(subscriber.borrow().msgs.len(), 2);
+}
+
+#[test]
+fn test_qos2_inbound_exactly_once() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber = subscribed_client(&mut server, "topic", 0);
+
+    let pub_bytes = vec![0x34, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 7, 1];
+    let dup_bytes = vec![0x3c, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 7, 1];
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+    assert_eq!(publisher.borrow().last_msg(), &[0x50u8, 2, 0, 7][..]);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1]]);
+
+    //the PUBREC got lost so the client sends it again
+    assert_eq!(server.new_message(publisher.clone(), &dup_bytes), true);
+    assert_eq!(publisher.borrow().last_msg(), &[0x50u8, 2, 0, 7][..]);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1]]);
+
+    assert_eq!(server.new_message(publisher.clone(), &[0x62, 2, 0, 7]), true);
+    assert_eq!(publisher.borrow().last_msg(), &[0x70u8, 2, 0, 7][..]);
+
+    //and now the PUBCOMP got lost
+    assert_eq!(server.new_message(publisher.clone(), &[0x62, 2, 0, 7]), true);
+    assert_eq!(publisher.borrow().last_msg(), &[0x70u8, 2, 0, 7][..]);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1]]);
+
+    //the packet identifier is free to be used again
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1], vec![1]]);
+}
+
+#[test]
+fn test_qos2_pubrel_unknown_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+
+    assert_eq!(server.new_message(client.clone(), &[0x62, 2, 0, 7]), true);
+    assert_eq!(client.borrow().last_msg(), &[0x70u8, 3, 0, 7, 0x92][..]);
+}
+
+#[test]
+fn test_qos2_outbound() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber = subscribed_client(&mut server, "topic", 2);
+
+    let pub_bytes = vec![0x34, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 7, 1];
+    assert_eq!(server.new_message(publisher.clone(), &pub_bytes), true);
+    assert_eq!(subscriber.borrow().last_msg(),
+               &[0x34u8, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 1, 1][..]);
+
+    //no PUBREC yet, send it again
+    let later = Instant::now() + Duration::from_secs(RETRANSMIT_SECS + 1);
+    server.tick(later);
+    assert_eq!(subscriber.borrow().last_msg(),
+               &[0x3cu8, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 1, 1][..]);
+
+    //a PUBACK doesn't do anything for QoS 2
+    assert_eq!(server.new_message(subscriber.clone(), &[0x40, 2, 0, 1]), true);
+    assert_eq!(server.new_message(subscriber.clone(), &[0x50, 2, 0, 1]), true);
+    assert_eq!(subscriber.borrow().last_msg(), &[0x62u8, 2, 0, 1][..]);
+
+    //no PUBCOMP yet, it's PUBREL that gets sent again and not the message
+    let msgs_len = subscriber.borrow().msgs.len();
+    let later = Instant::now() + Duration::from_secs(RETRANSMIT_SECS + 1);
+    server.tick(later);
+    assert_eq!(subscriber.borrow().msgs.len(), msgs_len + 1);
+    assert_eq!(subscriber.borrow().last_msg(), &[0x62u8, 2, 0, 1][..]);
+
+    assert_eq!(server.new_message(subscriber.clone(), &[0x70, 2, 0, 1]), true);
+    server.tick(later + Duration::from_secs(RETRANSMIT_SECS + 1));
+    assert_eq!(subscriber.borrow().msgs.len(), msgs_len + 1);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1], vec![1]]);
+}
+
+#[test]
+fn test_qos2_outbound_refused_v5() {
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let mut session = Session::new(client.clone(), message::ProtocolVersion::V5);
+
+    let publish = message::Publish { qos: 2, ..message::Publish::new("t", &[]) };
+    broker::Subscriber::new_message(&mut session, &publish);
+    assert_eq!(session.inflight.len(), 1);
+
+    session.received(&message::PubRec { packet_id: 1, reason_code: 0x97, properties: vec![] });
+    assert_eq!(session.inflight.len(), 0);
+    assert_eq!(client.borrow().msgs.len(), 1); //no PUBREL
+}
+
+#[test]
+fn test_unsubscribe() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+    let subscriber = subscribed_client(&mut server, "topic", 0);
+
+    assert_eq!(server.new_message(publisher.clone(), &[0x30, 8, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 1]),
+               true);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1]]);
+
+    let unsub_bytes = vec![
+        0xa2, 0x10, //fixed header
+        0x01, 0x02, //message ID
+        0x00, 0x05, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8,
+        0x00, 0x05, 'o' as u8, 't' as u8, 'h' as u8, 'e' as u8, 'r' as u8,
+        ];
+    assert_eq!(server.new_message(subscriber.clone(), &unsub_bytes), true);
+    assert_eq!(subscriber.borrow().last_msg(), &[0xb0u8, 2, 1, 2][..]);
+
+    assert_eq!(server.new_message(publisher.clone(), &[0x30, 8, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 2]),
+               true);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1]]);
+}
+
+#[test]
+fn test_unsubscribe_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+    assert_eq!(server.new_message(client.clone(), &[0x82, 9, 0, 1, 0, 0, 3, 't' as u8, 'o' as u8, 'p' as u8, 0]),
+               true);
+    assert_eq!(client.borrow().last_msg(), &[0x90u8, 4, 0, 1, 0, 0][..]);
+
+    let unsub_bytes = vec![
+        0xa2, 0x0d, //fixed header
+        0x00, 0x07, //message ID
+        0x00, //properties
+        0x00, 0x03, 't' as u8, 'o' as u8, 'p' as u8,
+        0x00, 0x03, 'o' as u8, 'f' as u8, 'f' as u8,
+        ];
+    assert_eq!(server.new_message(client.clone(), &unsub_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0xb0u8, 5, 0, 7, 0, 0, 0x11][..]);
+}
+
+#[cfg(test)]
+fn subscribe_bytes_qos(topic: &str, qos: u8) -> Vec<u8> {
+    let mut bytes = subscribe_bytes(topic, 1);
+    let len = bytes.len();
+    bytes[len - 1] = qos;
+    bytes
 }
 
 #[cfg(test)]
-impl TestClient {
-    fn new() -> Self {
-        TestClient { msgs: vec![], payloads: vec![] }
-    }
-
-    fn last_msg(&self) -> &[u8] {
-        self.msgs.last().expect("TestClient has no last message")
+fn publish_bytes(topic: &str, qos: u8, payload: &[u8]) -> Vec<u8> {
+    let id_len = if qos > 0 { 2 } else { 0 };
+    let mut bytes = vec![0x30u8 | qos << 1, (2 + topic.len() + id_len + payload.len()) as u8, 0, topic.len() as u8];
+    bytes.append(&mut string_to_bytes(topic));
+    if qos > 0 {
+        bytes.append(&mut vec![0, 0x42]);
     }
+    bytes.extend_from_slice(payload);
+    bytes
+}
 
-    fn read(&self, buffer: &mut [u8], bytes: &[u8]) -> usize {
-        for i in 0..bytes.len() {
-            buffer[i] = bytes[i];
-        }
-        bytes.len()
-    }
+#[test]
+fn test_persistent_session() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+    let resume_bytes = connect_bytes(&message::Connect {
+        clean_session: false,
+        ..connect_packet(message::ProtocolVersion::V311, "sleepy")
+    });
+    assert_eq!(server.new_message(subscriber.clone(), &resume_bytes), true);
+    assert_eq!(subscriber.borrow().last_msg(), &CONNACK_OK);
+    assert_eq!(server.new_message(subscriber.clone(), &subscribe_bytes_qos("topic", 1)), true);
+    server.client_disconnected(subscriber.clone());
+
+    //QoS 0 isn't kept for clients that aren't there
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 1, &[1])), true);
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 0, &[2])), true);
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 1, &[3])), true);
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
+
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(subscriber.clone(), &resume_bytes), true);
+    assert_eq!(subscriber.borrow().msgs[0], &[0x20u8, 2, 1, 0]);
+    assert_eq!(subscriber.borrow().msgs[1],
+               &[0x32u8, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 1, 1]);
+    assert_eq!(subscriber.borrow().msgs[2],
+               &[0x32u8, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 2, 3]);
+
+    //still subscribed
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 0, &[4])), true);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1], vec![3], vec![4]]);
 }
 
-#[cfg(test)]
-impl broker::Subscriber for TestClient {
-    fn new_message(&mut self, bytes: &[u8]) {
-        self.msgs.push(bytes.to_vec());
-        if message::message_type(bytes) == message::MqttType::Publish {
-            self.payloads.push(message::publish_payload(bytes).to_vec());
-        }
-    }
+#[test]
+fn test_persistent_session_resends_inflight() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+    let resume_bytes = connect_bytes(&message::Connect {
+        clean_session: false,
+        ..connect_packet(message::ProtocolVersion::V311, "sleepy")
+    });
+    assert_eq!(server.new_message(subscriber.clone(), &resume_bytes), true);
+    assert_eq!(server.new_message(subscriber.clone(), &subscribe_bytes_qos("topic", 1)), true);
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 1, &[1])), true);
+    assert_eq!(subscriber.borrow().payloads, vec![vec![1]]);
+    server.client_disconnected(subscriber.clone());
+
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(subscriber.clone(), &resume_bytes), true);
+    assert_eq!(subscriber.borrow().msgs.len(), 2);
+    assert_eq!(subscriber.borrow().last_msg(),
+               &[0x3au8, 10, 0, 5, 't' as u8, 'o' as u8, 'p' as u8, 'i' as u8, 'c' as u8, 0, 1, 1][..]);
 }
 
+#[test]
+fn test_clean_session_discards_persistent() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+    let resume_bytes = connect_bytes(&message::Connect {
+        clean_session: false,
+        ..connect_packet(message::ProtocolVersion::V311, "sleepy")
+    });
+    assert_eq!(server.new_message(subscriber.clone(), &resume_bytes), true);
+    assert_eq!(server.new_message(subscriber.clone(), &subscribe_bytes_qos("topic", 1)), true);
+    server.client_disconnected(subscriber.clone());
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 1, &[1])), true);
+
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(subscriber.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "sleepy"))), true);
+    assert_eq!(subscriber.borrow().msgs, vec![CONNACK_OK.to_vec()]);
+
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 0, &[2])), true);
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
+
+    //and a clean session isn't kept either
+    server.client_disconnected(subscriber.clone());
+    let subscriber = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(subscriber.clone(), &resume_bytes), true);
+    assert_eq!(subscriber.borrow().msgs, vec![CONNACK_OK.to_vec()]);
+}
 
 #[test]
-fn test_connect() {
-    let connect_bytes = &[
-        0x10u8, 0x2a, // fixed header
-        0x00, 0x06, 'M' as u8, 'Q' as u8, 'I' as u8, 's' as u8, 'd' as u8, 'p' as u8,
-        0x03, // protocol version
-        0xcc, // connection flags 1100111x user, pw, !wr, w(01), w, !c, x
-        0x00, 0x0a, // keepalive of 100
-        0x00, 0x03, 'c' as u8, 'i' as u8, 'd' as u8, // client ID
-        0x00, 0x04, 'w' as u8, 'i' as u8, 'l' as u8, 'l' as u8, // will topic
-        0x00, 0x04, 'w' as u8, 'm' as u8, 's' as u8, 'g' as u8, // will msg
-        0x00, 0x07, 'g' as u8, 'l' as u8, 'i' as u8, 'f' as u8, 't' as u8, 'e' as u8, 'l' as u8, // username
-        0x00, 0x02, 'p' as u8, 'w' as u8, // password
-        ][0..];
+fn test_session_expiry_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    let connect_bytes = connect_bytes(&message::Connect {
+        clean_session: false,
+        properties: vec![message::Property::SessionExpiryInterval(10)],
+        ..connect_packet(message::ProtocolVersion::V5, "cid")
+    });
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 3, 0, 0, 0][..]);
+    server.client_disconnected(client.clone());
+
+    server.tick(Instant::now() + Duration::from_secs(5));
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 3, 1, 0, 0][..]);
+    server.client_disconnected(client.clone());
+
+    server.tick(Instant::now() + Duration::from_secs(11));
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 3, 0, 0, 0][..]);
+}
 
+#[test]
+fn test_session_expiry_v311() {
     let mut server = Server::<TestClient>::new(false);
+    server.session_expiry(10);
+
     let client = Rc::new(RefCell::new(TestClient::new()));
+    let resume_bytes = connect_bytes(&message::Connect {
+        clean_session: false,
+        ..connect_packet(message::ProtocolVersion::V311, "cid")
+    });
+    assert_eq!(server.new_message(client.clone(), &resume_bytes), true);
+    server.client_disconnected(client.clone());
+
+    server.tick(Instant::now() + Duration::from_secs(5));
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &resume_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 2, 1, 0][..]);
+    server.client_disconnected(client.clone());
 
-    server.new_message(client.clone(), connect_bytes);
-    assert_eq!(client.borrow().msgs.len(), 1);
+    server.tick(Instant::now() + Duration::from_secs(11));
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &resume_bytes), true);
     assert_eq!(client.borrow().last_msg(), &CONNACK_OK);
 }
 
-
 #[test]
-fn test_ping() {
-    let ping_bytes =  &[0xc0u8, 0][0..];
-
+fn test_max_queued() {
     let mut server = Server::<TestClient>::new(false);
-    let client = Rc::new(RefCell::new(TestClient::new()));
-    let client = client.clone();
+    server.max_queued(2);
+    let publisher = connected_client(&mut server);
 
-    server.new_message(client.clone(), ping_bytes);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let resume_bytes = connect_bytes(&message::Connect {
+        clean_session: false,
+        ..connect_packet(message::ProtocolVersion::V311, "meter")
+    });
+    assert_eq!(server.new_message(client.clone(), &resume_bytes), true);
+    assert_eq!(server.new_message(client.clone(), &subscribe_bytes_qos("topic", 1)), true);
+    server.client_disconnected(client.clone());
+
+    for payload in 1 .. 4 {
+        assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 1, &[payload])), true);
+    }
 
-    assert_eq!(client.borrow().last_msg(), &PING_RESP);
+    //the oldest one didn't make the cut
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &resume_bytes), true);
+    assert_eq!(client.borrow().payloads, vec![vec![2], vec![3]]);
 }
 
+#[test]
+fn test_session_takeover() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+
+    let old = Rc::new(RefCell::new(TestClient::new()));
+    let resume_bytes = connect_bytes(&message::Connect {
+        clean_session: false,
+        ..connect_packet(message::ProtocolVersion::V311, "meter")
+    });
+    assert_eq!(server.new_message(old.clone(), &resume_bytes), true);
+    assert_eq!(server.new_message(old.clone(), &subscribe_bytes_qos("topic", 1)), true);
+
+    let new = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(new.clone(), &resume_bytes), true);
+    assert_eq!(old.borrow().closed, true);
+    assert_eq!(old.borrow().msgs.len(), 2); //no DISCONNECT before MQTT 5
+    assert_eq!(new.borrow().closed, false);
+    assert_eq!(new.borrow().last_msg(), &[0x20u8, 2, 1, 0][..]);
+
+    //the event loop finds out about the old socket going away later on
+    server.client_disconnected(old.clone());
+
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 0, &[1])), true);
+    assert_eq!(old.borrow().payloads.len(), 0);
+    assert_eq!(new.borrow().payloads, vec![vec![1]]);
+}
 
 #[test]
-fn test_pings_all_at_once() {
-    let ping_bytes = &[0xc0u8, 0, 0xc0, 0, 0xc0, 0, 0xc0, 0][0..];
+fn test_session_takeover_clean_session() {
+    let mut server = Server::<TestClient>::new(false);
+    let publisher = connected_client(&mut server);
+
+    let old = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(old.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "meter"))), true);
+    assert_eq!(server.new_message(old.clone(), &subscribe_bytes_qos("topic", 1)), true);
+
+    let new = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(new.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "meter"))), true);
+    assert_eq!(old.borrow().closed, true);
+    assert_eq!(new.borrow().last_msg(), &CONNACK_OK);
+
+    assert_eq!(server.new_message(publisher.clone(), &publish_bytes("topic", 0, &[1])), true);
+    assert_eq!(old.borrow().payloads.len(), 0);
+    assert_eq!(new.borrow().payloads.len(), 0);
+}
 
+#[test]
+fn test_session_takeover_v5() {
     let mut server = Server::<TestClient>::new(false);
-    let mut stream = Stream::new();
-    let client = Rc::new(RefCell::new(TestClient::new()));
-    let client = client.clone();
 
-    let bytes_read = client.borrow_mut().read(stream.buffer(), ping_bytes);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
+    let old = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(old.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
 
-    let client = client.clone();
-    assert_eq!(client.borrow().msgs.len(), 4);
-    for msg in &client.borrow().msgs {
-        assert_eq!(msg, &PING_RESP);
-    }
+    let new = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(new.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "cid"))), true);
+    assert_eq!(old.borrow().closed, true);
+    assert_eq!(old.borrow().last_msg(), &[0xe0u8, 1, 0x8e][..]);
+    assert_eq!(new.borrow().closed, false);
 }
 
 #[test]
-fn test_pings_multiple_time_unbroken() {
-    let ping_bytes = &[0xc0u8, 0, 0xc0, 0][0..];
+fn test_will_on_abnormal_disconnect() {
+    let mut server = Server::<TestClient>::new(false);
+    let subscriber = subscribed_client(&mut server, "will", 0);
 
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let will_bytes = connect_bytes(&message::Connect {
+        will: Some(will_packet()),
+        ..connect_packet(message::ProtocolVersion::V311, "meter")
+    });
+    assert_eq!(server.new_message(client.clone(), &will_bytes), true);
+    assert_eq!(client.borrow().last_msg(), &CONNACK_OK);
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
+
+    server.client_disconnected(client.clone());
+    assert_eq!(subscriber.borrow().last_msg(),
+               &[0x30u8, 10, 0, 4, 'w' as u8, 'i' as u8, 'l' as u8, 'l' as u8, 'w' as u8, 'm' as u8, 's' as u8, 'g' as u8][..]);
+}
+
+#[test]
+fn test_will_on_protocol_violation() {
     let mut server = Server::<TestClient>::new(false);
-    let mut stream = Stream::new();
+    let subscriber = subscribed_client(&mut server, "will", 0);
+
     let client = Rc::new(RefCell::new(TestClient::new()));
-    let client = client.clone();
+    let will_bytes = connect_bytes(&message::Connect {
+        will: Some(will_packet()),
+        ..connect_packet(message::ProtocolVersion::V311, "meter")
+    });
+    assert_eq!(server.new_message(client.clone(), &will_bytes), true);
+    assert_eq!(server.new_message(client.clone(), &[0x36, 5, 0, 1, 't' as u8, 0, 7]), true);
+    assert_eq!(client.borrow().closed, true);
+    server.client_disconnected(client.clone());
+    assert_eq!(subscriber.borrow().payloads, vec![b"wmsg".to_vec()]);
+}
 
-    let bytes_read = client.borrow_mut().read(stream.buffer(), ping_bytes);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
-    assert_eq!(client.borrow().msgs.len(), 2);
+#[test]
+fn test_no_will_on_disconnect() {
+    let mut server = Server::<TestClient>::new(false);
+    let subscriber = subscribed_client(&mut server, "will", 0);
 
-    let bytes_read = client.borrow_mut().read(stream.buffer(), ping_bytes);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
-    assert_eq!(client.borrow().msgs.len(), 4);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let will_bytes = connect_bytes(&message::Connect {
+        will: Some(will_packet()),
+        ..connect_packet(message::ProtocolVersion::V311, "meter")
+    });
+    assert_eq!(server.new_message(client.clone(), &will_bytes), true);
+    assert_eq!(server.new_message(client.clone(), &[0xe0, 0]), false);
+    server.client_disconnected(client.clone());
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
+}
 
-    for msg in &client.borrow().msgs {
-        assert_eq!(msg, &PING_RESP);
+#[cfg(test)]
+fn will_delay_connect(clean_start: bool) -> message::Connect {
+    message::Connect {
+        clean_session: clean_start,
+        will: Some(message::Will {
+            properties: vec![message::Property::WillDelayInterval(10)],
+            ..will_packet()
+        }),
+        properties: vec![message::Property::SessionExpiryInterval(60)],
+        ..connect_packet(message::ProtocolVersion::V5, "cid")
     }
 }
 
-
 #[test]
-fn test_pings_broken() {
-    let ping_fst = &[0xc0u8][0..];
-    let ping_snd = &[0u8][0..];
-
+fn test_will_delay_v5() {
     let mut server = Server::<TestClient>::new(false);
-    let mut stream = Stream::new();
+    let subscriber = subscribed_client(&mut server, "will", 0);
+
     let client = Rc::new(RefCell::new(TestClient::new()));
-    let client = client.clone();
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&will_delay_connect(false))), true);
+    server.client_disconnected(client.clone());
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
+
+    server.tick(Instant::now() + Duration::from_secs(5));
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
+    server.tick(Instant::now() + Duration::from_secs(11));
+    assert_eq!(subscriber.borrow().payloads, vec![b"wmsg".to_vec()]);
+}
 
-    let bytes_read = client.borrow_mut().read(stream.buffer(), ping_fst);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
-    assert_eq!(client.borrow().msgs.len(), 0);
+#[test]
+fn test_will_delay_v5_reconnect() {
+    let mut server = Server::<TestClient>::new(false);
+    let subscriber = subscribed_client(&mut server, "will", 0);
 
-    let bytes_read = client.borrow_mut().read(stream.buffer(), ping_snd);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
-    assert_eq!(client.borrow().msgs.len(), 1);
+    //back in time, no will
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&will_delay_connect(false))), true);
+    server.client_disconnected(client.clone());
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&will_delay_connect(false))), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 3, 1, 0, 0][..]);
+    server.tick(Instant::now() + Duration::from_secs(11));
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
 
-    let bytes_read = client.borrow_mut().read(stream.buffer(), ping_fst);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
-    assert_eq!(client.borrow().msgs.len(), 1);
+    //back in time but starting afresh, the old session is over and so is the wait
+    server.client_disconnected(client.clone());
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&will_delay_connect(true))), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 3, 0, 0, 0][..]);
+    assert_eq!(subscriber.borrow().payloads, vec![b"wmsg".to_vec()]);
+}
 
-    let bytes_read = client.borrow_mut().read(stream.buffer(), ping_snd);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
-    assert_eq!(client.borrow().msgs.len(), 2);
+#[test]
+fn test_will_with_will_message_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    let subscriber = subscribed_client(&mut server, "will", 0);
 
-    for msg in &client.borrow().msgs {
-        assert_eq!(msg, &PING_RESP);
-    }
+    let connect_bytes = connect_bytes(&message::Connect {
+        will: Some(will_packet()), //no will delay
+        ..will_delay_connect(true)
+    });
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(server.new_message(client.clone(), &[0xe0, 1, 0x04]), false);
+    server.client_disconnected(client.clone());
+    assert_eq!(subscriber.borrow().payloads, vec![b"wmsg".to_vec()]);
 }
 
-#[cfg(test)]
-fn string_to_bytes(s: &str) -> Vec<u8> {
-    let mut vec: Vec<u8> = vec![];
-    for c in s.chars() {
-        vec.push(c as u8);
-    }
-    vec
+#[test]
+fn test_keep_alive() {
+    let mut server = Server::<TestClient>::new(false);
+    let subscriber = subscribed_client(&mut server, "will", 0);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let will_bytes = connect_bytes(&message::Connect {
+        will: Some(will_packet()),
+        ..connect_packet(message::ProtocolVersion::V311, "meter")
+    });
+    assert_eq!(server.new_message(client.clone(), &will_bytes), true); //keep alive of 10
+
+    server.tick(Instant::now() + Duration::from_secs(14));
+    assert_eq!(client.borrow().closed, false);
+    assert_eq!(subscriber.borrow().payloads.len(), 0);
+
+    server.tick(Instant::now() + Duration::from_secs(16));
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().msgs.len(), 1); //just the CONNACK
+    assert_eq!(subscriber.borrow().payloads, vec![b"wmsg".to_vec()]);
+
+    //the event loop catching up doesn't publish it twice
+    server.client_disconnected(client.clone());
+    assert_eq!(subscriber.borrow().payloads, vec![b"wmsg".to_vec()]);
 }
 
-#[cfg(test)]
-fn subscribe_bytes(topic: &str, msg_id: u16) -> Vec<u8> {
-    let mut fixed_header = [0x8cu8, 5 + topic.len() as u8].to_vec();
-    let mut msg_part = vec![0, msg_id as u8];
-    let mut topic_header = vec![0, topic.len() as u8];
-    let mut string_bytes = string_to_bytes(topic);
-    let mut bytes = vec![];
-    bytes.append(&mut fixed_header);
-    bytes.append(&mut msg_part);
-    bytes.append(&mut topic_header);
-    bytes.append(&mut string_bytes);
-    bytes.push(0u8); //qos
-    bytes
+#[test]
+fn test_keep_alive_zero() {
+    let mut server = Server::<TestClient>::new(false);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let connect_bytes = connect_bytes(&message::Connect {
+        keep_alive: 0,
+        ..connect_packet(message::ProtocolVersion::V311, "meter")
+    });
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+
+    server.tick(Instant::now() + Duration::from_secs(60 * 60 * 24));
+    assert_eq!(client.borrow().closed, false);
 }
 
 #[test]
-fn test_suback_bytes() {
-    let subscribe_bytes = &subscribe_bytes("topic", 42)[..];
-    let qos: u8 = 0;
-    let suback_bytes = &[0x90u8, 3, 0, 42, qos][..];
+fn test_server_keep_alive_v5() {
+    let mut server = Server::<TestClient>::new(false);
+    server.override_keep_alive(30);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+    assert_eq!(client.borrow().last_msg(), &[0x20u8, 6, 0, 0, 3, 0x13, 0, 30][..]);
+
+    //3.1.1 clients can't be told
+    let client311 = Rc::new(RefCell::new(TestClient::new()));
+    let connect_bytes = connect_bytes(&message::Connect {
+        keep_alive: 0,
+        ..connect_packet(message::ProtocolVersion::V311, "meter")
+    });
+    assert_eq!(server.new_message(client311.clone(), &connect_bytes), true);
+    assert_eq!(client311.borrow().last_msg(), &CONNACK_OK);
+
+    server.tick(Instant::now() + Duration::from_secs(44));
+    assert_eq!(client.borrow().closed, false);
+
+    server.tick(Instant::now() + Duration::from_secs(46));
+    assert_eq!(client.borrow().closed, true);
+    assert_eq!(client.borrow().last_msg(), &[0xe0u8, 1, 0x8d][..]);
+    assert_eq!(client311.borrow().closed, false);
+}
 
+#[test]
+fn test_packet_before_connect_closes_connection() {
     let mut server = Server::<TestClient>::new(false);
-    let mut stream = Stream::new();
     let client = Rc::new(RefCell::new(TestClient::new()));
-    let client = client.clone();
 
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &subscribe_bytes);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
-    assert_eq!(client.borrow().last_msg(), suback_bytes);
+    assert_eq!(server.new_message(client.clone(), &[0xc0, 0]), false);
+    assert_eq!(server.new_message(client.clone(), &subscribe_bytes("topic", 1)), false);
+    assert_eq!(client.borrow().msgs.len(), 0);
 }
 
-
 #[test]
-fn test_subscribe() {
+fn test_second_connect_closes_connection() {
     let mut server = Server::<TestClient>::new(false);
-    let mut stream = Stream::new();
-    let client = Rc::new(RefCell::new(TestClient::new()));
-    let client = client.clone();
 
-    let pub_bytes = vec![
-        0x3c, 0x0d, //fixed header
-        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,//topic name
-        0x00, 0x21, //message ID
-        'b' as u8, 'o' as u8, 'r' as u8, 'g' as u8, //payload
-        ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &pub_bytes);
-    assert_eq!(stream.handle_messages(bytes_read, &mut server, client.clone()), true);
-    assert_eq!(client.borrow().payloads.len(), 0);
+    let client = connected_client(&mut server);
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "again"))), true);
+    assert_eq!(client.borrow().msgs.len(), 0);
+    assert_eq!(client.borrow().closed, true);
 
-    let sub_bytes = vec![
-        0x8b, 0x13, //fixed header
-        0x00, 0x21, //message ID
-        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,
-        0x01, //qos
-        0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,
-        0x02, //qos
-        ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &sub_bytes);
-    assert_eq!(stream.handle_messages(bytes_read, &mut server, client.clone()), true);
+    let client5 = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client5.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+    assert_eq!(server.new_message(client5.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+    assert_eq!(client5.borrow().last_msg(), &[0xe0u8, 1, 0x82][..]);
+    assert_eq!(client5.borrow().closed, true);
 
-    let pub_bytes = vec![
-        0x3c, 0x0d, //fixed header
-        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,//topic name
-        0x00, 0x21, //message ID
-        'b' as u8, 'o' as u8, 'r' as u8, 'g' as u8, //payload
-        ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &pub_bytes);
-    assert_eq!(stream.handle_messages(bytes_read, &mut server, client.clone()), true);
+    //and that's the end of it
+    assert_eq!(server.new_message(client5.clone(), &[0xc0, 0]), true);
+    assert_eq!(client5.borrow().last_msg(), &[0xe0u8, 1, 0x82][..]);
+}
 
-    let pub_bytes = vec![
-        0x3c, 0x0d, //fixed header
-        0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,//topic name
-        0x00, 0x21, //message ID
-        'f' as u8, 'o' as u8, 'o' as u8,//payload
-        ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &pub_bytes);
-    assert_eq!(stream.handle_messages(bytes_read, &mut server, client.clone()), true);
+#[test]
+fn test_connect_timeout() {
+    let mut server = Server::<TestClient>::new(false);
+    server.connect_timeout(5);
 
-    let pub_bytes = vec![
-        0x3c, 0x0c, //fixed header
-        0x00, 0x05, 't' as u8, 'h' as u8, 'i' as u8, 'r' as u8, 'd' as u8,//topic name
-        0x00, 0x21, //message ID
-        'f' as u8, 'o' as u8, 'o' as u8,//payload
-        //--
-        0xe0, 0, //disconnect
-        ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &pub_bytes);
-    //false since last msg is disconnect
-    assert_eq!(stream.handle_messages(bytes_read, &mut server, client.clone()), false);
+    let silent = Rc::new(RefCell::new(TestClient::new()));
+    server.client_connected(silent.clone());
+    let chatty = Rc::new(RefCell::new(TestClient::new()));
+    server.client_connected(chatty.clone());
+    assert_eq!(server.new_message(chatty.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "cid"))), true);
 
-    assert_eq!(client.borrow().payloads, vec![b"borg".to_vec(), b"foo".to_vec()]);
+    server.tick(Instant::now() + Duration::from_secs(4));
+    assert_eq!(silent.borrow().closed, false);
+
+    server.tick(Instant::now() + Duration::from_secs(6));
+    assert_eq!(silent.borrow().closed, true);
+    assert_eq!(chatty.borrow().closed, false);
+
+    //too late now, it's ignored until the socket's gone
+    assert_eq!(server.new_message(silent.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "cid"))), true);
+    assert_eq!(silent.borrow().msgs.len(), 0);
+    server.client_disconnected(silent.clone());
 }
 
 #[test]
-fn test_bug1() {
+fn test_taken_over_client_ignored() {
     let mut server = Server::<TestClient>::new(false);
-    let mut stream = Stream::new();
-    let client = Rc::new(RefCell::new(TestClient::new()));
-    let client = client.clone();
 
-    //printed from when the bug occurred
-    let first = vec![
-        48, 30, 0, 12, 108, 111, 97, 100, 116, 101, 115, 116, 47, 49, 54, 54
-            ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &first);
-    stream.handle_messages(bytes_read, &mut server, client.clone());
-    assert_eq!(client.borrow().payloads.len(), 0);
+    let old = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(old.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "meter"))), true);
+    let new = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(new.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "meter"))), true);
+
+    //whatever was still on its way from the old socket
+    assert_eq!(server.new_message(old.clone(), &[0xc0, 0]), true);
+    assert_eq!(old.borrow().msgs.len(), 1);
 }
 
+#[test]
+fn test_connack_identifier_rejected() {
+    let mut server = Server::<TestClient>::new(false);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&message::Connect {
+        clean_session: false,
+        ..connect_packet(message::ProtocolVersion::V311, "")
+    })), true);
+    assert_eq!(client.borrow().msgs, vec![vec![0x20u8, 2, 0, 2]]);
+    assert_eq!(client.borrow().closed, true);
+    server.client_disconnected(client.clone());
+
+    //clean sessions can do without an id
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, ""))), true);
+    assert_eq!(client.borrow().last_msg(), &CONNACK_OK);
 
+    //3.1 client ids are 23 characters at most
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    let connect_bytes = connect_bytes(&connect_packet(message::ProtocolVersion::V31, &"x".repeat(24)));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes), true);
+    assert_eq!(client.borrow().msgs, vec![vec![0x20u8, 2, 0, 2]]);
+}
 
 #[test]
-fn test_publish_in_two_msgs() {
+fn test_connack_credentials() {
     let mut server = Server::<TestClient>::new(false);
-    let mut stream = Stream::new();
+    server.require_credentials("user", b"secret");
+
     let client = Rc::new(RefCell::new(TestClient::new()));
-    let client = client.clone();
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "cid"))), true);
+    assert_eq!(client.borrow().msgs, vec![vec![0x20u8, 2, 0, 5]]);
+    assert_eq!(client.borrow().closed, true);
 
-    let sub_bytes = vec![
-        0x8b, 0x13, //fixed header
-        0x00, 0x21, //message ID
-        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,
-        0x01, //qos
-        0x00, 0x06, 's' as u8, 'e' as u8, 'c' as u8, 'o' as u8, 'n' as u8, 'd' as u8,
-        0x02, //qos
-        ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &sub_bytes);
-    assert_eq!(stream.handle_messages(bytes_read, &mut server, client.clone()), true);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&message::Connect {
+        username: Some("user".to_string()),
+        password: Some(b"wrong".to_vec()),
+        ..connect_packet(message::ProtocolVersion::V311, "cid")
+    })), true);
+    assert_eq!(client.borrow().msgs, vec![vec![0x20u8, 2, 0, 4]]);
 
-    //1st part of message
-    let pub_bytes = vec![
-        0x3c, 0x0d, //fixed header
-        0x00, 0x05, 'f' as u8, 'i' as u8, 'r' as u8, 's' as u8, 't' as u8,//topic name
-        ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &pub_bytes);
-    assert_eq!(stream.handle_messages(bytes_read, &mut server, client.clone()), true);
-    assert_eq!(client.borrow().payloads.len(), 0);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&message::Connect {
+        username: Some("user".to_string()),
+        password: None,
+        ..connect_packet(message::ProtocolVersion::V5, "cid")
+    })), true);
+    assert_eq!(client.borrow().msgs, vec![vec![0x20u8, 3, 0, 0x86, 0]]);
 
-    //2nd part of message
-    let pub_bytes = vec![
-        0x00, 0x21, //message ID
-        'b' as u8, 'o' as u8, 'r' as u8, 'g' as u8, //payload
-        ];
-    let bytes_read = client.borrow_mut().read(stream.buffer(), &pub_bytes);
-    assert_eq!(stream.handle_messages(bytes_read, &mut server, client.clone()), true);
-    assert_eq!(client.borrow().payloads.len(), 1);
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V5, "cid"))), true);
+    assert_eq!(client.borrow().msgs, vec![vec![0x20u8, 3, 0, 0x87, 0]]);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&message::Connect {
+        username: Some("user".to_string()),
+        password: Some(b"secret".to_vec()),
+        ..connect_packet(message::ProtocolVersion::V311, "cid")
+    })), true);
+    assert_eq!(client.borrow().msgs, vec![CONNACK_OK.to_vec()]);
+}
+
+#[test]
+fn test_connack_server_unavailable() {
+    let mut server = Server::<TestClient>::new(false);
+    server.max_clients(1);
+
+    let client = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(client.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "first"))), true);
+
+    let refused = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(refused.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "second"))), true);
+    assert_eq!(refused.borrow().msgs, vec![vec![0x20u8, 2, 0, 3]]);
+    assert_eq!(refused.borrow().closed, true);
+    //a refused client doesn't leave a will or anything else behind
+    server.client_disconnected(refused.clone());
+
+    //taking over isn't one more
+    let takeover = Rc::new(RefCell::new(TestClient::new()));
+    assert_eq!(server.new_message(takeover.clone(), &connect_bytes(&connect_packet(message::ProtocolVersion::V311, "first"))), true);
+    assert_eq!(takeover.borrow().msgs, vec![CONNACK_OK.to_vec()]);
 }